@@ -0,0 +1,12 @@
+DROP INDEX expense_participants_user_id_index;
+
+DROP TABLE expense_participants;
+
+DELETE FROM notifications
+WHERE data ->> 'kind' = 'direct_expense'
+OR (data ->> 'kind' = 'payment' AND (data ->> 'expense_id')::integer IN (SELECT id FROM expenses WHERE group_id IS NULL));
+
+DELETE FROM expenses
+WHERE group_id IS NULL;
+
+ALTER TABLE expenses ALTER COLUMN group_id SET NOT NULL;
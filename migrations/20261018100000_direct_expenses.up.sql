@@ -0,0 +1,16 @@
+-- expenses shared between users outside of any group
+ALTER TABLE expenses ALTER COLUMN group_id DROP DEFAULT;
+
+ALTER TABLE expenses ALTER COLUMN group_id DROP NOT NULL;
+
+-- participants of direct expenses (the ones without a group)
+CREATE TABLE expense_participants (
+    expense_id integer NOT NULL,
+    user_id varchar NOT NULL,
+    -- keys
+    FOREIGN KEY (expense_id) REFERENCES expenses (id),
+    FOREIGN KEY (user_id) REFERENCES users (id),
+    PRIMARY KEY (expense_id, user_id)
+);
+
+CREATE INDEX expense_participants_user_id_index ON expense_participants (user_id);
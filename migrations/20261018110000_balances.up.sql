@@ -0,0 +1,16 @@
+-- materialized balances between each pair of members, kept in sync with the expenses of the group
+-- existing expenses are loaded with `splitwizz-service rebuild-balances`
+CREATE TABLE balances (
+    group_id integer NOT NULL,
+    -- what `user_id` owes to `other_user_id` (negative when it is owed)
+    user_id varchar NOT NULL,
+    other_user_id varchar NOT NULL,
+    currency_id integer NOT NULL,
+    amount double precision NOT NULL DEFAULT 0,
+    -- keys
+    FOREIGN KEY (group_id) REFERENCES GROUPS (id),
+    FOREIGN KEY (user_id) REFERENCES users (id),
+    FOREIGN KEY (other_user_id) REFERENCES users (id),
+    FOREIGN KEY (currency_id) REFERENCES currencies (id),
+    PRIMARY KEY (group_id, user_id, other_user_id, currency_id)
+);
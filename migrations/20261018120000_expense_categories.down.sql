@@ -0,0 +1,3 @@
+DROP INDEX expenses_category_index;
+
+ALTER TABLE expenses DROP COLUMN category;
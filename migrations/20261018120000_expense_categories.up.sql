@@ -0,0 +1,3 @@
+ALTER TABLE expenses ADD COLUMN category varchar;
+
+CREATE INDEX expenses_category_index ON expenses (category);
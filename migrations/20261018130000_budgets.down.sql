@@ -0,0 +1,8 @@
+DELETE FROM notifications
+WHERE data ->> 'kind' = 'budget_alert';
+
+DROP INDEX budgets_group_id_index;
+
+DROP TABLE budgets;
+
+DROP TYPE budget_period;
@@ -0,0 +1,25 @@
+CREATE TYPE budget_period AS ENUM ('weekly', 'monthly', 'yearly');
+
+CREATE TABLE budgets (
+    -- ids
+    id serial NOT NULL PRIMARY KEY,
+    group_id integer NOT NULL,
+    -- status
+    deleted boolean NOT NULL DEFAULT FALSE,
+    -- data
+    amount double precision NOT NULL,
+    currency_id integer NOT NULL,
+    period budget_period NOT NULL,
+    category varchar,
+    -- fraction of the amount that triggers the alerts
+    threshold double precision NOT NULL DEFAULT 1,
+    -- created action
+    created_by_id varchar NOT NULL,
+    created_at timestamp with time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    -- keys
+    FOREIGN KEY (group_id) REFERENCES GROUPS (id),
+    FOREIGN KEY (currency_id) REFERENCES currencies (id),
+    FOREIGN KEY (created_by_id) REFERENCES users (id)
+);
+
+CREATE INDEX budgets_group_id_index ON budgets (group_id);
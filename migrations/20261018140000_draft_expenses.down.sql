@@ -0,0 +1,3 @@
+DELETE FROM expenses WHERE draft = TRUE;
+
+ALTER TABLE expenses DROP COLUMN draft;
@@ -0,0 +1 @@
+ALTER TABLE expenses ADD COLUMN draft boolean NOT NULL DEFAULT FALSE;
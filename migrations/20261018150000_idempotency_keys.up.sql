@@ -0,0 +1,19 @@
+CREATE TABLE idempotency_keys (
+    -- ids
+    user_id varchar NOT NULL,
+    key varchar NOT NULL,
+    -- request
+    method varchar NOT NULL,
+    path varchar NOT NULL,
+    -- response, empty while the request is in progress
+    status smallint,
+    content_type varchar,
+    body bytea,
+    -- created action
+    created_at timestamp with time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    -- keys
+    PRIMARY KEY (user_id, key),
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
+
+CREATE INDEX idempotency_keys_created_at_index ON idempotency_keys (created_at);
@@ -0,0 +1,13 @@
+DROP TRIGGER notifications_changes ON notifications;
+
+DROP TRIGGER expenses_changes ON expenses;
+
+DROP TRIGGER memberships_changes ON memberships;
+
+DROP TRIGGER groups_changes ON GROUPS;
+
+DROP FUNCTION record_change;
+
+DROP TABLE changes;
+
+DROP TYPE change_kind;
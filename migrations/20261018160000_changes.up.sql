@@ -0,0 +1,53 @@
+CREATE TYPE change_kind AS ENUM ('group', 'membership', 'expense', 'notification');
+
+-- every row created or updated gets a new entry, the sequence is the cursor of `/changes`
+CREATE TABLE changes (
+    seq bigserial NOT NULL PRIMARY KEY,
+    kind change_kind NOT NULL,
+    -- ids of the changed row, depending on the kind
+    group_id integer,
+    user_id varchar,
+    expense_id integer,
+    notification_id integer,
+    created_at timestamp with time zone DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE INDEX changes_group_id_index ON changes (group_id);
+
+CREATE INDEX changes_user_id_index ON changes (user_id);
+
+CREATE FUNCTION record_change() RETURNS trigger AS $$
+BEGIN
+    IF TG_TABLE_NAME = 'groups' THEN
+        INSERT INTO changes (kind, group_id) VALUES ('group', NEW.id);
+    ELSIF TG_TABLE_NAME = 'memberships' THEN
+        INSERT INTO changes (kind, group_id, user_id) VALUES ('membership', NEW.group_id, NEW.user_id);
+    ELSIF TG_TABLE_NAME = 'expenses' THEN
+        INSERT INTO changes (kind, group_id, expense_id) VALUES ('expense', NEW.group_id, NEW.id);
+    ELSIF TG_TABLE_NAME = 'notifications' THEN
+        INSERT INTO changes (kind, user_id, notification_id) VALUES ('notification', NEW.user_id, NEW.id);
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER groups_changes AFTER INSERT OR UPDATE ON GROUPS
+    FOR EACH ROW EXECUTE FUNCTION record_change();
+
+CREATE TRIGGER memberships_changes AFTER INSERT OR UPDATE ON memberships
+    FOR EACH ROW EXECUTE FUNCTION record_change();
+
+CREATE TRIGGER expenses_changes AFTER INSERT OR UPDATE ON expenses
+    FOR EACH ROW EXECUTE FUNCTION record_change();
+
+CREATE TRIGGER notifications_changes AFTER INSERT OR UPDATE ON notifications
+    FOR EACH ROW EXECUTE FUNCTION record_change();
+
+-- existing rows are the starting point of the clients
+INSERT INTO changes (kind, group_id) SELECT 'group', id FROM GROUPS;
+
+INSERT INTO changes (kind, group_id, user_id) SELECT 'membership', group_id, user_id FROM memberships;
+
+INSERT INTO changes (kind, group_id, expense_id) SELECT 'expense', group_id, id FROM expenses;
+
+INSERT INTO changes (kind, user_id, notification_id) SELECT 'notification', user_id, id FROM notifications;
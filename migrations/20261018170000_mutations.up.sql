@@ -0,0 +1,14 @@
+CREATE TABLE mutations (
+    -- ids, generated by the clients
+    user_id varchar NOT NULL,
+    id uuid NOT NULL,
+    -- empty while the mutation is being applied
+    outcome jsonb,
+    -- created action
+    created_at timestamp with time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    -- keys
+    PRIMARY KEY (user_id, id),
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
+
+CREATE INDEX mutations_created_at_index ON mutations (created_at);
@@ -0,0 +1,12 @@
+-- maintenance tasks run once by the first replica starting after they are added
+CREATE TABLE pending_tasks (
+    name varchar NOT NULL,
+    -- created action
+    created_at timestamp with time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    -- keys
+    PRIMARY KEY (name)
+);
+
+-- loads the balances of the expenses created before they were materialized
+INSERT INTO pending_tasks (name)
+    VALUES ('rebuild-balances');
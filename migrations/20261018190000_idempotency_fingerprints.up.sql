@@ -0,0 +1,4 @@
+-- hash of the method, path and body of the request that claimed the key, keys claimed before
+-- have none and are only checked by method and path
+ALTER TABLE idempotency_keys
+    ADD COLUMN fingerprint bytea;
@@ -0,0 +1,4 @@
+DROP INDEX changes_txid_index;
+
+ALTER TABLE changes
+    DROP COLUMN txid;
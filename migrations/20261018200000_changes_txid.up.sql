@@ -0,0 +1,6 @@
+-- transaction that recorded the change, the cursor of `/changes` is the oldest transaction still
+-- in flight, so changes committed later can't fall behind it
+ALTER TABLE changes
+    ADD COLUMN txid xid8 NOT NULL DEFAULT pg_current_xact_id();
+
+CREATE INDEX changes_txid_index ON changes (txid);
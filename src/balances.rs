@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Datelike, Duration, Utc};
+
+use crate::models::{
+    Balance, BalanceSnapshot, CurrencyId, Expense, FriendBalance, GroupId, HistoryBucket,
+    InternalMembership, PairBalance, UserId,
+};
+
+/// Balance between two users of a group in a given currency, see [`PairBalance`].
+pub type PairKey = (GroupId, UserId, UserId, CurrencyId);
+
+/// Computes the balances between the given users out of a list of expenses, debts with users
+/// outside of `user_ids` are ignored.
+pub fn compute_balances(
+    user_ids: impl IntoIterator<Item = UserId>,
+    expenses: &[Expense],
+) -> HashMap<UserId, Balance> {
+    let mut balances = HashMap::<UserId, Balance>::from_iter(user_ids.into_iter().map(|user_id| {
+        (
+            user_id.clone(),
+            Balance {
+                user_id,
+                total: HashMap::default(),
+                owes: HashMap::default(),
+            },
+        )
+    }));
+
+    // TODO - simplified balances - moliva - 2024/03/22
+
+    for expense in expenses {
+        let payer = expense.split_strategy.payer();
+
+        for (ower, share) in expense.split_strategy.shares(expense.amount) {
+            if &ower == payer {
+                // nothing to do here
+                continue;
+            }
+
+            add_debt(&mut balances, &ower, payer, expense.currency_id, share);
+        }
+    }
+
+    balances
+}
+
+/// Computes the net total of each of the given users at the end of every bucket of time with
+/// expenses, `expenses` are expected in chronological order.
+pub fn compute_balance_history(
+    user_ids: impl IntoIterator<Item = UserId>,
+    expenses: &[Expense],
+    bucket: HistoryBucket,
+) -> Vec<BalanceSnapshot> {
+    let mut totals = HashMap::<UserId, HashMap<CurrencyId, f64>>::from_iter(
+        user_ids.into_iter().map(|u| (u, HashMap::default())),
+    );
+
+    let mut history = Vec::<BalanceSnapshot>::default();
+    for expense in expenses {
+        let date = bucket_start(expense.date, bucket);
+
+        // close the current bucket when the expense belongs to a newer one
+        if history.last().map(|s| s.date) != Some(date) {
+            history.push(BalanceSnapshot {
+                date,
+                totals: HashMap::default(),
+            });
+        }
+
+        let payer = expense.split_strategy.payer();
+        for (ower, share) in expense.split_strategy.shares(expense.amount) {
+            if &ower == payer {
+                continue;
+            }
+
+            if let Some(total) = totals.get_mut(&ower) {
+                *total.entry(expense.currency_id).or_default() += share;
+            }
+            if let Some(total) = totals.get_mut(payer) {
+                *total.entry(expense.currency_id).or_default() -= share;
+            }
+        }
+
+        history.last_mut().expect("current bucket").totals = totals.clone();
+    }
+
+    history
+}
+
+fn bucket_start(date: DateTime<Utc>, bucket: HistoryBucket) -> DateTime<Utc> {
+    let day = date.date_naive();
+
+    let start = match bucket {
+        HistoryBucket::Day => day,
+        HistoryBucket::Week => day - Duration::days(day.weekday().num_days_from_monday() as i64),
+        HistoryBucket::Month => day.with_day(1).expect("first day of month"),
+    };
+
+    start.and_hms_opt(0, 0, 0).expect("midnight").and_utc()
+}
+
+/// Builds the balances of the given users out of the materialized pairs of their group, pairs of
+/// users outside of `user_ids` are ignored.
+pub fn balances_from_pairs(
+    user_ids: impl IntoIterator<Item = UserId>,
+    pairs: Vec<PairBalance>,
+) -> HashMap<UserId, Balance> {
+    let mut balances = compute_balances(user_ids, &[]);
+
+    for pair in pairs {
+        if let Some(balance) = balances.get_mut(&pair.user_id) {
+            *balance.total.entry(pair.currency_id).or_default() += pair.amount;
+            *balance
+                .owes
+                .entry(pair.other_user_id)
+                .or_default()
+                .entry(pair.currency_id)
+                .or_default() += pair.amount;
+        }
+    }
+
+    balances
+}
+
+/// Changes that the given group expenses introduce into the materialized pairs, in both
+/// directions, direct expenses are ignored.
+pub fn pair_changes<'a>(expenses: impl IntoIterator<Item = &'a Expense>) -> HashMap<PairKey, f64> {
+    let mut changes = HashMap::<PairKey, f64>::default();
+
+    for expense in expenses {
+        let Some(group_id) = expense.group_id else {
+            continue;
+        };
+        let payer = expense.split_strategy.payer();
+
+        for (ower, share) in expense.split_strategy.shares(expense.amount) {
+            if &ower == payer {
+                continue;
+            }
+
+            *changes
+                .entry((group_id, ower.clone(), payer.clone(), expense.currency_id))
+                .or_default() += share;
+            *changes
+                .entry((group_id, payer.clone(), ower, expense.currency_id))
+                .or_default() -= share;
+        }
+    }
+
+    changes
+}
+
+/// Aggregates the balances of `user_id` with every other member of the given groups and with the
+/// participants of their direct expenses, computing each group on its own and keeping the
+/// breakdown per group.
+pub fn compute_friend_balances(
+    user_id: &UserId,
+    memberships: Vec<InternalMembership>,
+    expenses: Vec<Expense>,
+    direct_expenses: Vec<Expense>,
+) -> Vec<FriendBalance> {
+    let mut members = HashMap::<GroupId, Vec<UserId>>::default();
+    for membership in memberships {
+        members
+            .entry(membership.group_id)
+            .or_default()
+            .push(membership.user_id);
+    }
+
+    let mut group_expenses = HashMap::<GroupId, Vec<Expense>>::default();
+    for expense in expenses {
+        if let Some(group_id) = expense.group_id {
+            group_expenses.entry(group_id).or_default().push(expense);
+        }
+    }
+
+    let mut friends = HashMap::<UserId, FriendBalance>::default();
+
+    for (group_id, user_ids) in members {
+        let expenses = group_expenses.remove(&group_id).unwrap_or_default();
+
+        let mut balances = compute_balances(user_ids, &expenses);
+        let Some(balance) = balances.remove(user_id) else {
+            continue;
+        };
+
+        for (other, debts) in balance.owes {
+            let friend_balance = friend_entry(&mut friends, other);
+
+            for (currency_id, amount) in debts {
+                *friend_balance.total.entry(currency_id).or_default() += amount;
+                *friend_balance
+                    .groups
+                    .entry(group_id)
+                    .or_default()
+                    .entry(currency_id)
+                    .or_default() += amount;
+            }
+        }
+    }
+
+    let participants = direct_expenses
+        .iter()
+        .flat_map(|e| e.split_strategy.participants())
+        .chain([user_id.clone()]);
+
+    let mut balances = compute_balances(participants, &direct_expenses);
+    if let Some(balance) = balances.remove(user_id) {
+        for (other, debts) in balance.owes {
+            let friend_balance = friend_entry(&mut friends, other);
+
+            for (currency_id, amount) in debts {
+                *friend_balance.total.entry(currency_id).or_default() += amount;
+                *friend_balance.direct.entry(currency_id).or_default() += amount;
+            }
+        }
+    }
+
+    friends.into_values().collect()
+}
+
+fn friend_entry(
+    friends: &mut HashMap<UserId, FriendBalance>,
+    friend: UserId,
+) -> &mut FriendBalance {
+    friends
+        .entry(friend.clone())
+        .or_insert_with(|| FriendBalance {
+            user_id: friend,
+            total: HashMap::default(),
+            groups: HashMap::default(),
+            direct: HashMap::default(),
+        })
+}
+
+fn add_debt(
+    balances: &mut HashMap<UserId, Balance>,
+    ower: &UserId,
+    payer: &UserId,
+    currency_id: CurrencyId,
+    amount: f64,
+) {
+    // add bill to ower in relation to payer
+    if let Some(balance) = balances.get_mut(ower) {
+        *balance.total.entry(currency_id).or_default() += amount;
+        *balance
+            .owes
+            .entry(payer.clone())
+            .or_default()
+            .entry(currency_id)
+            .or_default() += amount;
+    }
+
+    // decrease bill from payer in relation to ower
+    if let Some(balance) = balances.get_mut(payer) {
+        *balance.total.entry(currency_id).or_default() -= amount;
+        *balance
+            .owes
+            .entry(ower.clone())
+            .or_default()
+            .entry(currency_id)
+            .or_default() -= amount;
+    }
+}
@@ -0,0 +1,718 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use tokio::sync::watch;
+
+use crate::models;
+
+pub type Bus = Arc<dyn EventBus>;
+pub type BusError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Time the events are kept in the queues of the users for the clients to catch up.
+pub const EVENTS_RETENTION_SECONDS: i64 = 10 * 60;
+/// Time after the last sync for users to be considered logged out.
+pub const PRESENCE_TIMEOUT_SECONDS: i64 = 60;
+
+/// Message published to a topic, once for every pattern it matched.
+pub struct BusMessage {
+    pub topic: String,
+    /// pattern followed by the subscriber that matched the topic
+    pub pattern: String,
+    pub payload: String,
+}
+
+/// Messages of the topics matching the patterns followed by the subscriber.
+pub struct Subscription {
+    pub subscriber: Box<dyn Subscriber>,
+    pub messages: BoxStream<'static, BusMessage>,
+}
+
+/// Follows and stops following patterns of topics, where `*` matches any sequence of characters.
+#[async_trait]
+pub trait Subscriber: Send {
+    async fn subscribe(&mut self, pattern: &str) -> Result<(), BusError>;
+
+    async fn unsubscribe(&mut self, pattern: &str) -> Result<(), BusError>;
+}
+
+/// Reads the queues of the users, waiting on a connection of its own so that long reads don't
+/// hold back the rest of the bus.
+#[async_trait]
+pub trait QueueReader: Send {
+    /// Waits up to the timeout for entries in the queue of the user after the cursor. Entries
+    /// are not removed, every device reads the queue from its own cursor.
+    async fn read_after(
+        &mut self,
+        email: &str,
+        cursor: &str,
+        count: usize,
+        timeout: Duration,
+    ) -> Result<Vec<(String, String)>, BusError>;
+}
+
+/// Topics, queues of events per user, presence of the users and leases among the replicas.
+#[async_trait]
+pub trait EventBus: Send + Sync {
+    async fn publish(&self, topic: &str, payload: &str) -> Result<(), BusError>;
+
+    async fn subscribe(&self) -> Result<Subscription, BusError>;
+
+    /// Appends the payload to the queue of the user, dropping the entries past the retention.
+    async fn push(&self, email: &str, payload: &str) -> Result<(), BusError>;
+
+    /// Reader for a single stream of events, to be kept for as long as the stream lasts.
+    async fn reader(&self) -> Result<Box<dyn QueueReader>, BusError>;
+
+    /// Reader for a single long poll, to be dropped right after it.
+    async fn poll_reader(&self) -> Result<Box<dyn QueueReader>, BusError>;
+
+    /// Cursor of the last entry in the queue of the user, `None` when empty.
+    async fn last_cursor(&self, email: &str) -> Result<Option<String>, BusError>;
+
+    /// Remembers how far the device of the user got in their queue, kept as long as the entries.
+    async fn save_cursor(&self, email: &str, device: &str, cursor: &str) -> Result<(), BusError>;
+
+    /// Cursor last saved for the device of the user.
+    async fn device_cursor(&self, email: &str, device: &str) -> Result<Option<String>, BusError>;
+
+    /// Records that the user is still around, returns whether they just came online.
+    async fn touch_presence(&self, email: &str) -> Result<bool, BusError>;
+
+    /// Removes the users that did not sync lately, every one of them is returned to a single
+    /// caller even when many replicas sweep at the same time. Users are online up to the
+    /// presence timeout included.
+    async fn sweep_presence(&self) -> Result<Vec<String>, BusError>;
+
+    /// Users that synced lately.
+    async fn online_users(&self) -> Result<Vec<String>, BusError>;
+
+    /// Takes (or keeps) the lease for the holder, returns whether they hold it. Leases expire
+    /// unless renewed by their holder, so that other replicas can take over.
+    async fn acquire_lease(&self, lease: &str, holder: &str, millis: u64)
+        -> Result<bool, BusError>;
+}
+
+pub async fn publish_topic(bus: Bus, topic: String, payload: String) {
+    if let Err(e) = bus.publish(&topic, &payload).await {
+        eprintln!("bus error publishing to `{}`:\n{}", topic, e);
+    }
+}
+
+/// Publishes the event to the topic on behalf of its author.
+pub async fn publish_event(bus: Bus, topic: String, author: String, event: models::Event) {
+    let payload = models::EventPayload { author, event };
+    let payload = serde_json::to_string(&payload).expect("serialized event");
+
+    publish_topic(bus, topic, payload).await
+}
+
+// *****************************************************************************************************
+// *************** In-process bus ***************
+// *****************************************************************************************************
+
+/// Event bus living in the process, for running without redis. Nothing is shared with other
+/// replicas.
+pub struct InMemoryBus {
+    state: Arc<Mutex<State>>,
+    /// bumped on every push to wake up the readers waiting for entries
+    pushed: watch::Sender<()>,
+    /// seconds since the epoch, the presence of the users is timed with it
+    clock: Arc<dyn Fn() -> i64 + Send + Sync>,
+}
+
+#[derive(Default)]
+struct State {
+    subscribers: Vec<InMemorySubscription>,
+    queues: HashMap<String, VecDeque<(EntryId, String)>>,
+    last_id: EntryId,
+    /// cursors of the devices, by user and device
+    cursors: HashMap<(String, String), String>,
+    presence: HashMap<String, i64>,
+    leases: HashMap<String, (String, Instant)>,
+}
+
+/// Millis and sequence, same as the ids of redis streams.
+type EntryId = (u64, u64);
+
+struct InMemorySubscription {
+    patterns: Arc<Mutex<HashSet<String>>>,
+    sender: UnboundedSender<BusMessage>,
+}
+
+struct InMemorySubscriber {
+    patterns: Arc<Mutex<HashSet<String>>>,
+}
+
+struct InMemoryReader {
+    state: Arc<Mutex<State>>,
+    pushed: watch::Receiver<()>,
+}
+
+impl Default for InMemoryBus {
+    fn default() -> Self {
+        let (pushed, _) = watch::channel(());
+
+        Self {
+            state: Arc::default(),
+            pushed,
+            clock: Arc::new(|| chrono::Utc::now().timestamp()),
+        }
+    }
+}
+
+impl InMemoryBus {
+    #[cfg(test)]
+    pub fn with_clock(clock: impl Fn() -> i64 + Send + Sync + 'static) -> Self {
+        Self {
+            clock: Arc::new(clock),
+            ..Self::default()
+        }
+    }
+}
+
+impl InMemoryReader {
+    fn entries_after(&self, email: &str, after: EntryId, count: usize) -> Vec<(String, String)> {
+        let state = self.state.lock().expect("bus state");
+
+        state
+            .queues
+            .get(email)
+            .into_iter()
+            .flatten()
+            .filter(|(id, _)| *id > after)
+            .take(count)
+            .map(|(id, payload)| (format_id(*id), payload.clone()))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl EventBus for InMemoryBus {
+    async fn publish(&self, topic: &str, payload: &str) -> Result<(), BusError> {
+        let mut state = self.state.lock().expect("bus state");
+
+        state.subscribers.retain(|s| !s.sender.is_closed());
+
+        for subscription in state.subscribers.iter() {
+            let patterns = subscription.patterns.lock().expect("patterns");
+            for pattern in patterns.iter().filter(|p| matches_pattern(p, topic)) {
+                let _ = subscription.sender.unbounded_send(BusMessage {
+                    topic: topic.to_owned(),
+                    pattern: pattern.clone(),
+                    payload: payload.to_owned(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn subscribe(&self) -> Result<Subscription, BusError> {
+        let (sender, receiver) = unbounded();
+        let patterns = Arc::new(Mutex::new(HashSet::new()));
+
+        self.state
+            .lock()
+            .expect("bus state")
+            .subscribers
+            .push(InMemorySubscription {
+                patterns: patterns.clone(),
+                sender,
+            });
+
+        Ok(Subscription {
+            subscriber: Box::new(InMemorySubscriber { patterns }),
+            messages: receiver.boxed(),
+        })
+    }
+
+    async fn push(&self, email: &str, payload: &str) -> Result<(), BusError> {
+        {
+            let mut state = self.state.lock().expect("bus state");
+
+            let now = chrono::Utc::now().timestamp_millis() as u64;
+            let (millis, sequence) = state.last_id;
+            let id = if now > millis {
+                (now, 0)
+            } else {
+                (millis, sequence + 1)
+            };
+            state.last_id = id;
+
+            let min_millis = now.saturating_sub(EVENTS_RETENTION_SECONDS as u64 * 1000);
+
+            let queue = state.queues.entry(email.to_owned()).or_default();
+            while queue.front().is_some_and(|(id, _)| id.0 < min_millis) {
+                queue.pop_front();
+            }
+            queue.push_back((id, payload.to_owned()));
+        }
+
+        self.pushed.send_replace(());
+
+        Ok(())
+    }
+
+    async fn reader(&self) -> Result<Box<dyn QueueReader>, BusError> {
+        Ok(Box::new(InMemoryReader {
+            state: self.state.clone(),
+            pushed: self.pushed.subscribe(),
+        }))
+    }
+
+    async fn poll_reader(&self) -> Result<Box<dyn QueueReader>, BusError> {
+        self.reader().await
+    }
+
+    async fn last_cursor(&self, email: &str) -> Result<Option<String>, BusError> {
+        let state = self.state.lock().expect("bus state");
+
+        Ok(state
+            .queues
+            .get(email)
+            .and_then(|q| q.back())
+            .map(|(id, _)| format_id(*id)))
+    }
+
+    async fn save_cursor(&self, email: &str, device: &str, cursor: &str) -> Result<(), BusError> {
+        let mut state = self.state.lock().expect("bus state");
+
+        state
+            .cursors
+            .insert((email.to_owned(), device.to_owned()), cursor.to_owned());
+
+        Ok(())
+    }
+
+    async fn device_cursor(&self, email: &str, device: &str) -> Result<Option<String>, BusError> {
+        let state = self.state.lock().expect("bus state");
+
+        Ok(state
+            .cursors
+            .get(&(email.to_owned(), device.to_owned()))
+            .cloned())
+    }
+
+    async fn touch_presence(&self, email: &str) -> Result<bool, BusError> {
+        let mut state = self.state.lock().expect("bus state");
+        let now = (self.clock)();
+
+        Ok(state.presence.insert(email.to_owned(), now).is_none())
+    }
+
+    async fn sweep_presence(&self) -> Result<Vec<String>, BusError> {
+        let mut state = self.state.lock().expect("bus state");
+        let cutoff = (self.clock)() - PRESENCE_TIMEOUT_SECONDS;
+
+        let gone = state
+            .presence
+            .iter()
+            .filter(|(_, at)| **at < cutoff)
+            .map(|(user, _)| user.clone())
+            .collect::<Vec<_>>();
+
+        for user in gone.iter() {
+            state.presence.remove(user);
+        }
+
+        Ok(gone)
+    }
+
+    async fn online_users(&self) -> Result<Vec<String>, BusError> {
+        let state = self.state.lock().expect("bus state");
+        let cutoff = (self.clock)() - PRESENCE_TIMEOUT_SECONDS;
+
+        Ok(state
+            .presence
+            .iter()
+            .filter(|(_, at)| **at >= cutoff)
+            .map(|(user, _)| user.clone())
+            .collect())
+    }
+
+    async fn acquire_lease(
+        &self,
+        lease: &str,
+        holder: &str,
+        millis: u64,
+    ) -> Result<bool, BusError> {
+        let mut state = self.state.lock().expect("bus state");
+        let now = Instant::now();
+
+        let taken = state
+            .leases
+            .get(lease)
+            .is_some_and(|(current, expires)| current != holder && *expires > now);
+        if taken {
+            return Ok(false);
+        }
+
+        let expires = now + Duration::from_millis(millis);
+        state
+            .leases
+            .insert(lease.to_owned(), (holder.to_owned(), expires));
+
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl QueueReader for InMemoryReader {
+    async fn read_after(
+        &mut self,
+        email: &str,
+        cursor: &str,
+        count: usize,
+        timeout: Duration,
+    ) -> Result<Vec<(String, String)>, BusError> {
+        let after = parse_id(cursor).ok_or("invalid cursor")?;
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        loop {
+            // marked as seen before looking at the queue, no push gets lost in between
+            self.pushed.borrow_and_update();
+
+            let entries = self.entries_after(email, after, count);
+            if !entries.is_empty() {
+                return Ok(entries);
+            }
+
+            match tokio::time::timeout_at(deadline, self.pushed.changed()).await {
+                Ok(Ok(())) => continue,
+                _ => return Ok(vec![]),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Subscriber for InMemorySubscriber {
+    async fn subscribe(&mut self, pattern: &str) -> Result<(), BusError> {
+        self.patterns
+            .lock()
+            .expect("patterns")
+            .insert(pattern.to_owned());
+
+        Ok(())
+    }
+
+    async fn unsubscribe(&mut self, pattern: &str) -> Result<(), BusError> {
+        self.patterns.lock().expect("patterns").remove(pattern);
+
+        Ok(())
+    }
+}
+
+/// Whether the topic matches the pattern, where `*` matches any sequence of characters.
+fn matches_pattern(pattern: &str, topic: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == topic,
+        Some((prefix, rest)) => {
+            let Some(topic) = topic.strip_prefix(prefix) else {
+                return false;
+            };
+
+            topic
+                .char_indices()
+                .map(|(i, _)| i)
+                .chain([topic.len()])
+                .any(|i| matches_pattern(rest, &topic[i..]))
+        }
+    }
+}
+
+/// Id of the entry in the cursor, the sequence being optional.
+fn parse_id(cursor: &str) -> Option<EntryId> {
+    let (millis, sequence) = cursor.split_once('-').unwrap_or((cursor, "0"));
+    Some((millis.parse().ok()?, sequence.parse().ok()?))
+}
+
+fn format_id((millis, sequence): EntryId) -> String {
+    format!("{}-{}", millis, sequence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NO_WAIT: Duration = Duration::from_millis(0);
+
+    async fn reader(bus: &InMemoryBus) -> Box<dyn QueueReader> {
+        bus.reader().await.unwrap()
+    }
+
+    fn payloads(entries: &[(String, String)]) -> Vec<&str> {
+        entries.iter().map(|(_, p)| p.as_str()).collect()
+    }
+
+    #[test]
+    fn matches_exact_topics() {
+        assert!(matches_pattern("sync", "sync"));
+        assert!(!matches_pattern("sync", "syncs"));
+        assert!(!matches_pattern("sync", "auth.login"));
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(matches_pattern("groups.1.*", "groups.1.expenses.created"));
+        assert!(matches_pattern("groups.1.*", "groups.1."));
+        assert!(!matches_pattern("groups.1.*", "groups.10.expenses.created"));
+        assert!(matches_pattern(
+            "users.*.groups.*.joined",
+            "users.a@b.c.groups.3.joined"
+        ));
+        assert!(!matches_pattern("users.*.joined", "users.a@b.c.left"));
+        assert!(matches_pattern("*", ""));
+    }
+
+    #[test]
+    fn parses_ids() {
+        assert_eq!(parse_id("1700000000000-3"), Some((1_700_000_000_000, 3)));
+        assert_eq!(parse_id("1700000000000"), Some((1_700_000_000_000, 0)));
+        assert_eq!(parse_id("0-0"), Some((0, 0)));
+        assert_eq!(parse_id(&format_id((12, 5))), Some((12, 5)));
+        assert_eq!(parse_id("abc"), None);
+        assert_eq!(parse_id("1-x"), None);
+        assert_eq!(parse_id(""), None);
+    }
+
+    #[actix_web::test]
+    async fn reads_after_the_cursor_without_removing() {
+        let bus = InMemoryBus::default();
+        let mut reader = reader(&bus).await;
+
+        for payload in ["a", "b", "c"] {
+            bus.push("user", payload).await.unwrap();
+        }
+
+        let all = reader.read_after("user", "0-0", 10, NO_WAIT).await.unwrap();
+        assert_eq!(payloads(&all), ["a", "b", "c"]);
+
+        let rest = reader
+            .read_after("user", &all[0].0, 10, NO_WAIT)
+            .await
+            .unwrap();
+        assert_eq!(payloads(&rest), ["b", "c"]);
+
+        // every device reads from its own cursor
+        let again = reader.read_after("user", "0-0", 2, NO_WAIT).await.unwrap();
+        assert_eq!(payloads(&again), ["a", "b"]);
+
+        let none = reader
+            .read_after("user", &all[2].0, 10, NO_WAIT)
+            .await
+            .unwrap();
+        assert!(none.is_empty());
+
+        assert_eq!(
+            bus.last_cursor("user").await.unwrap(),
+            Some(all[2].0.clone())
+        );
+        assert_eq!(bus.last_cursor("other").await.unwrap(), None);
+    }
+
+    #[actix_web::test]
+    async fn keeps_a_cursor_per_device() {
+        let bus = InMemoryBus::default();
+
+        bus.save_cursor("user", "phone", "1-0").await.unwrap();
+        bus.save_cursor("user", "laptop", "2-0").await.unwrap();
+        bus.save_cursor("user", "phone", "3-0").await.unwrap();
+
+        let cursor = |device| bus.device_cursor("user", device);
+        assert_eq!(cursor("phone").await.unwrap().as_deref(), Some("3-0"));
+        assert_eq!(cursor("laptop").await.unwrap().as_deref(), Some("2-0"));
+        assert_eq!(cursor("tablet").await.unwrap(), None);
+        assert_eq!(bus.device_cursor("other", "phone").await.unwrap(), None);
+    }
+
+    #[actix_web::test]
+    async fn delivers_once_per_matching_pattern() {
+        let bus = InMemoryBus::default();
+        let Subscription {
+            mut subscriber,
+            mut messages,
+        } = bus.subscribe().await.unwrap();
+
+        subscriber.subscribe("users.a.b@c.d.*").await.unwrap();
+        subscriber.subscribe("users.a.*").await.unwrap();
+
+        bus.publish("users.a.b@c.d.notifications", "n")
+            .await
+            .unwrap();
+
+        let mut patterns = vec![];
+        for _ in 0..2 {
+            let message = messages.next().await.unwrap();
+            assert_eq!(message.topic, "users.a.b@c.d.notifications");
+            assert_eq!(message.payload, "n");
+            patterns.push(message.pattern);
+        }
+        patterns.sort();
+        assert_eq!(patterns, ["users.a.*", "users.a.b@c.d.*"]);
+    }
+
+    #[actix_web::test]
+    async fn keeps_queues_apart() {
+        let bus = InMemoryBus::default();
+        let mut reader = reader(&bus).await;
+
+        bus.push("user", "a").await.unwrap();
+        bus.push("other", "b").await.unwrap();
+
+        let entries = reader.read_after("user", "0-0", 10, NO_WAIT).await.unwrap();
+        assert_eq!(payloads(&entries), ["a"]);
+    }
+
+    #[actix_web::test]
+    async fn waits_for_pushes() {
+        let bus = Arc::new(InMemoryBus::default());
+        let mut reader = reader(&bus).await;
+
+        let pusher = bus.clone();
+        actix_web::rt::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            pusher.push("user", "a").await.unwrap();
+        });
+
+        let entries = reader
+            .read_after("user", "0-0", 10, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(payloads(&entries), ["a"]);
+
+        let entries = reader
+            .read_after("user", &entries[0].0, 10, Duration::from_millis(20))
+            .await
+            .unwrap();
+        assert!(entries.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn rejects_invalid_cursors() {
+        let bus = InMemoryBus::default();
+        let mut reader = reader(&bus).await;
+
+        assert!(reader.read_after("user", "abc", 10, NO_WAIT).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn drops_entries_past_the_retention() {
+        let bus = InMemoryBus::default();
+        let mut reader = reader(&bus).await;
+
+        let expired = chrono::Utc::now().timestamp_millis() as u64
+            - (EVENTS_RETENTION_SECONDS as u64 + 1) * 1000;
+        bus.state
+            .lock()
+            .unwrap()
+            .queues
+            .entry("user".to_owned())
+            .or_default()
+            .push_back(((expired, 0), "old".to_owned()));
+
+        bus.push("user", "new").await.unwrap();
+
+        let entries = reader.read_after("user", "0-0", 10, NO_WAIT).await.unwrap();
+        assert_eq!(payloads(&entries), ["new"]);
+    }
+
+    #[actix_web::test]
+    async fn sweeps_users_past_the_presence_timeout() {
+        let bus = InMemoryBus::default();
+
+        assert!(bus.touch_presence("recent").await.unwrap());
+        assert!(!bus.touch_presence("recent").await.unwrap());
+
+        {
+            let mut state = bus.state.lock().unwrap();
+            let now = chrono::Utc::now().timestamp();
+            state
+                .presence
+                .insert("gone".to_owned(), now - PRESENCE_TIMEOUT_SECONDS - 5);
+        }
+
+        let mut online = bus.online_users().await.unwrap();
+        online.sort();
+        assert_eq!(online, ["recent"]);
+
+        assert_eq!(bus.sweep_presence().await.unwrap(), ["gone"]);
+        assert!(bus.sweep_presence().await.unwrap().is_empty());
+
+        // coming back counts as a new login
+        assert!(bus.touch_presence("gone").await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn online_and_swept_users_do_not_overlap() {
+        let now = 1_700_000_000;
+        let bus = InMemoryBus::with_clock(move || now);
+
+        for offset in [-1, 0, 1] {
+            let at = now - PRESENCE_TIMEOUT_SECONDS + offset;
+            bus.state
+                .lock()
+                .unwrap()
+                .presence
+                .insert(offset.to_string(), at);
+        }
+
+        let online = bus.online_users().await.unwrap();
+        let swept = bus.sweep_presence().await.unwrap();
+
+        assert_eq!(online.len() + swept.len(), 3);
+        assert!(online.iter().all(|user| !swept.contains(user)));
+        assert_eq!(swept, ["-1"]);
+    }
+
+    #[actix_web::test]
+    async fn leases_are_kept_by_their_holder() {
+        let bus = InMemoryBus::default();
+
+        assert!(bus.acquire_lease("lease", "a", 60_000).await.unwrap());
+        assert!(!bus.acquire_lease("lease", "b", 60_000).await.unwrap());
+        // renewed by the holder
+        assert!(bus.acquire_lease("lease", "a", 60_000).await.unwrap());
+        // leases are independent
+        assert!(bus.acquire_lease("other", "b", 60_000).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn expired_leases_are_taken_over() {
+        let bus = InMemoryBus::default();
+
+        assert!(bus.acquire_lease("lease", "a", 0).await.unwrap());
+        assert!(bus.acquire_lease("lease", "b", 60_000).await.unwrap());
+        assert!(!bus.acquire_lease("lease", "a", 60_000).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn delivers_to_matching_subscriptions() {
+        let bus = InMemoryBus::default();
+        let Subscription {
+            mut subscriber,
+            mut messages,
+        } = bus.subscribe().await.unwrap();
+
+        subscriber.subscribe("groups.1.*").await.unwrap();
+
+        bus.publish("groups.2.expenses", "skipped").await.unwrap();
+        bus.publish("groups.1.expenses", "delivered").await.unwrap();
+
+        let msg = messages.next().await.unwrap();
+        assert_eq!(msg.topic, "groups.1.expenses");
+        assert_eq!(msg.payload, "delivered");
+
+        subscriber.unsubscribe("groups.1.*").await.unwrap();
+        bus.publish("groups.1.expenses", "skipped").await.unwrap();
+        subscriber.subscribe("sync").await.unwrap();
+        bus.publish("sync", "user").await.unwrap();
+
+        assert_eq!(messages.next().await.unwrap().payload, "user");
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+
+use crate::balances::{compute_balances, PairKey};
+use crate::models::{Expense, GroupId, UserId};
+use crate::queries::{self, DbPool};
+
+/// Max difference tolerated between the materialized balances and a full recomputation.
+const TOLERANCE: f64 = 0.005;
+
+pub async fn rebuild_balances(pool: &DbPool) -> Result<(), sqlx::Error> {
+    println!("REBUILDING BALANCES");
+
+    let count = queries::rebuild_pair_balances(pool).await?;
+
+    println!("{count} balances rebuilt");
+    Ok(())
+}
+
+/// Rebuilds the balances once after the migration asking for it.
+pub async fn rebuild_pending_balances(pool: &DbPool) -> Result<(), sqlx::Error> {
+    if let Some(count) = queries::rebuild_pending_pair_balances(pool).await? {
+        println!("{count} pending balances rebuilt");
+    }
+
+    Ok(())
+}
+
+/// Compares the materialized balances with a full recomputation from the expenses log, returns
+/// whether both are consistent.
+pub async fn check_balances(pool: &DbPool) -> Result<bool, sqlx::Error> {
+    println!("CHECKING BALANCES");
+
+    let memberships = queries::find_all_memberships(pool).await?;
+    let expenses = queries::find_all_group_expenses(pool).await?;
+    let pairs = queries::find_all_pair_balances(pool).await?;
+
+    let mut members = HashMap::<GroupId, Vec<UserId>>::default();
+    for membership in memberships {
+        members
+            .entry(membership.group_id)
+            .or_default()
+            .push(membership.user_id);
+    }
+
+    let mut group_expenses = HashMap::<GroupId, Vec<Expense>>::default();
+    for expense in expenses {
+        group_expenses
+            .entry(expense.group_id.expect("group id"))
+            .or_default()
+            .push(expense);
+    }
+
+    let mut expected = HashMap::<PairKey, f64>::default();
+    for (group_id, user_ids) in members.iter() {
+        let expenses = group_expenses.remove(group_id).unwrap_or_default();
+
+        for (user_id, balance) in compute_balances(user_ids.clone(), &expenses) {
+            for (other_user_id, debts) in balance.owes {
+                for (currency_id, amount) in debts {
+                    expected.insert(
+                        (
+                            *group_id,
+                            user_id.clone(),
+                            other_user_id.clone(),
+                            currency_id,
+                        ),
+                        amount,
+                    );
+                }
+            }
+        }
+    }
+
+    // only pairs of current members are taken into account, same as the recomputation
+    let mut actual = HashMap::<PairKey, f64>::default();
+    for pair in pairs {
+        let is_member = members
+            .get(&pair.group_id)
+            .is_some_and(|user_ids| user_ids.contains(&pair.user_id));
+
+        if is_member {
+            actual.insert(
+                (
+                    pair.group_id,
+                    pair.user_id,
+                    pair.other_user_id,
+                    pair.currency_id,
+                ),
+                pair.amount,
+            );
+        }
+    }
+
+    let mut consistent = true;
+    for key in expected.keys().chain(actual.keys()) {
+        let expected_amount = expected.get(key).copied().unwrap_or_default();
+        let actual_amount = actual.get(key).copied().unwrap_or_default();
+
+        if (expected_amount - actual_amount).abs() > TOLERANCE {
+            let (group_id, user_id, other_user_id, currency_id) = key;
+            println!(
+                "group {group_id}: {user_id} owes {other_user_id} {actual_amount} in currency {currency_id}, expected {expected_amount}"
+            );
+            consistent = false;
+        }
+    }
+
+    if consistent {
+        println!("balances are consistent");
+    }
+
+    Ok(consistent)
+}
@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+
+use actix_web::web::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::models::{Balance, Currency, CurrencyId, Expense, ExpenseId, GroupId, User, UserId};
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all(serialize = "snake_case", deserialize = "snake_case"))]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+    #[serde(default)]
+    pub include_deleted: bool,
+}
+
+#[derive(Serialize)]
+pub struct ExportedUser {
+    pub id: UserId,
+    pub name: Option<String>,
+    pub email: String,
+}
+
+#[derive(Serialize)]
+pub struct ExportedShare {
+    pub user: ExportedUser,
+    pub amount: f64,
+}
+
+#[derive(Serialize)]
+pub struct ExportedExpense {
+    pub id: ExpenseId,
+    pub date: chrono::DateTime<chrono::Utc>,
+    pub description: String,
+    pub category: Option<String>,
+    pub deleted: bool,
+    pub currency: String,
+    pub amount: f64,
+    pub payer: ExportedUser,
+    pub shares: Vec<ExportedShare>,
+}
+
+/// What `user` owes to `other_user` (negative when it is owed).
+#[derive(Serialize)]
+pub struct ExportedBalance {
+    pub user: ExportedUser,
+    pub other_user: ExportedUser,
+    pub currency: String,
+    pub amount: f64,
+}
+
+/// Fields of the export that go before the expenses.
+#[derive(Serialize)]
+pub struct ExportHeader {
+    pub group_id: GroupId,
+    pub name: String,
+    pub exported_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Users and currencies referenced by the ledger of a group, resolving the expenses one at a time
+/// while they are streamed.
+pub struct ExportContext {
+    users: HashMap<UserId, User>,
+    currencies: HashMap<CurrencyId, String>,
+}
+
+impl ExportContext {
+    pub fn new(users: Vec<User>, currencies: Vec<Currency>) -> Self {
+        Self {
+            users: HashMap::from_iter(users.into_iter().map(|u| (u.id.clone(), u))),
+            currencies: HashMap::from_iter(currencies.into_iter().map(|c| (c.id, c.acronym))),
+        }
+    }
+
+    fn user(&self, id: &UserId) -> ExportedUser {
+        match self.users.get(id) {
+            Some(u) => ExportedUser {
+                id: u.id.clone(),
+                name: u.name.clone(),
+                email: u.email.clone(),
+            },
+            None => ExportedUser {
+                id: id.clone(),
+                name: None,
+                email: String::default(),
+            },
+        }
+    }
+
+    fn currency(&self, id: &CurrencyId) -> String {
+        self.currencies.get(id).cloned().unwrap_or_default()
+    }
+
+    pub fn expense(&self, e: Expense) -> ExportedExpense {
+        ExportedExpense {
+            id: e.id.expect("expense id"),
+            date: e.date,
+            payer: self.user(e.split_strategy.payer()),
+            shares: e
+                .split_strategy
+                .shares(e.amount)
+                .into_iter()
+                .map(|(u, amount)| ExportedShare {
+                    user: self.user(&u),
+                    amount,
+                })
+                .collect(),
+            description: e.description,
+            category: e.category,
+            deleted: e.deleted,
+            currency: self.currency(&e.currency_id),
+            amount: e.amount,
+        }
+    }
+
+    pub fn balances(&self, balances: HashMap<UserId, Balance>) -> Vec<ExportedBalance> {
+        let mut balances = balances
+            .into_values()
+            .flat_map(|b| {
+                b.owes.into_iter().flat_map(move |(other, debts)| {
+                    let user_id = b.user_id.clone();
+                    debts
+                        .into_iter()
+                        .map(move |(c, amount)| (user_id.clone(), other.clone(), c, amount))
+                })
+            })
+            .collect::<Vec<_>>();
+        balances.sort_by(|a, b| (&a.0, &a.1, a.2).cmp(&(&b.0, &b.1, b.2)));
+
+        balances
+            .into_iter()
+            .map(|(u, other, c, amount)| ExportedBalance {
+                user: self.user(&u),
+                other_user: self.user(&other),
+                currency: self.currency(&c),
+                amount,
+            })
+            .collect()
+    }
+}
+
+impl ExportFormat {
+    /// Opens the export, in JSON the expenses are an array within the object of the export.
+    pub fn header(self, header: &ExportHeader) -> Bytes {
+        match self {
+            ExportFormat::Json => {
+                let header = serde_json::to_string(header).expect("serialized header");
+                // the expenses go right after the fields of the header
+                let fields = header.strip_suffix('}').expect("json object");
+                Bytes::from(format!("{},\"expenses\":[", fields))
+            }
+            ExportFormat::Csv => csv_row(&[
+                "expense_id",
+                "date",
+                "description",
+                "category",
+                "deleted",
+                "currency",
+                "amount",
+                "payer_name",
+                "payer_email",
+                "participant_name",
+                "participant_email",
+                "share",
+            ]),
+        }
+    }
+
+    /// Renders an expense, in CSV as one row per share (or a single one without participants).
+    pub fn expense(self, expense: &ExportedExpense, first: bool) -> Bytes {
+        match self {
+            ExportFormat::Json => {
+                let expense = serde_json::to_string(expense).expect("serialized expense");
+                let separator = if first { "" } else { "," };
+                Bytes::from(format!("{}{}", separator, expense))
+            }
+            ExportFormat::Csv => {
+                let mut chunk = Vec::default();
+
+                let row = |name: &str, email: &str, share: &str| {
+                    csv_row(&[
+                        &expense.id.to_string(),
+                        &expense.date.to_rfc3339(),
+                        &expense.description,
+                        expense.category.as_deref().unwrap_or_default(),
+                        &expense.deleted.to_string(),
+                        &expense.currency,
+                        &expense.amount.to_string(),
+                        expense.payer.name.as_deref().unwrap_or_default(),
+                        &expense.payer.email,
+                        name,
+                        email,
+                        share,
+                    ])
+                };
+
+                for share in expense.shares.iter() {
+                    chunk.extend(row(
+                        share.user.name.as_deref().unwrap_or_default(),
+                        &share.user.email,
+                        &share.amount.to_string(),
+                    ));
+                }
+
+                // expenses without shares still get their row
+                if expense.shares.is_empty() {
+                    chunk.extend(row("", "", ""));
+                }
+
+                Bytes::from(chunk)
+            }
+        }
+    }
+
+    /// Closes the export with the balances, in CSV as their own section.
+    pub fn footer(self, balances: &[ExportedBalance]) -> Bytes {
+        match self {
+            ExportFormat::Json => {
+                let balances = serde_json::to_string(balances).expect("serialized balances");
+                Bytes::from(format!("],\"balances\":{}}}", balances))
+            }
+            ExportFormat::Csv => {
+                // balances go in their own section after an empty line
+                let mut chunk = b"\n".to_vec();
+                chunk.extend(csv_row(&[
+                    "user_name",
+                    "user_email",
+                    "other_user_name",
+                    "other_user_email",
+                    "currency",
+                    "owes",
+                ]));
+                for balance in balances {
+                    chunk.extend(csv_row(&[
+                        balance.user.name.as_deref().unwrap_or_default(),
+                        &balance.user.email,
+                        balance.other_user.name.as_deref().unwrap_or_default(),
+                        &balance.other_user.email,
+                        &balance.currency,
+                        &balance.amount.to_string(),
+                    ]));
+                }
+
+                Bytes::from(chunk)
+            }
+        }
+    }
+}
+
+fn csv_row(fields: &[&str]) -> Bytes {
+    let mut writer = csv::Writer::from_writer(Vec::default());
+    writer.write_record(fields).expect("csv record");
+
+    Bytes::from(writer.into_inner().expect("csv row"))
+}
@@ -0,0 +1,174 @@
+use actix_web::body::{to_bytes, BoxBody, MessageBody};
+use actix_web::dev::Payload;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::{
+    ErrorBadRequest, ErrorConflict, ErrorInternalServerError, ErrorNotFound, ErrorPayloadTooLarge,
+    ErrorUnprocessableEntity,
+};
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::web::{BytesMut, Data};
+use actix_web::{Error, HttpMessage, HttpResponse};
+use futures::{stream, StreamExt};
+
+use ::auth::identity::Identity;
+
+use crate::models::IdempotencyClaim;
+use crate::queries::DbPool;
+
+pub const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+const IDEMPOTENT_REPLAYED: &str = "Idempotent-Replayed";
+
+const MAX_KEY_LENGTH: usize = 255;
+
+/// Replays the stored response of mutating requests retried with the same `Idempotency-Key`
+/// instead of repeating their side effects. Wraps the mutating routes only.
+pub async fn idempotency(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let key = req
+        .headers()
+        .get(IDEMPOTENCY_KEY)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+
+    let Some(key) = key else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+        return Err(ErrorBadRequest(format!(
+            "{} must have between 1 and {} characters",
+            IDEMPOTENCY_KEY, MAX_KEY_LENGTH
+        )));
+    }
+
+    // unauthenticated requests are left to the routes
+    let Ok(identity) = req.extract::<Identity>().await else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    let email = identity.claims().email;
+
+    let pool = req.app_data::<Data<DbPool>>().expect("db pool").clone();
+    let method = req.method().to_string();
+    let path = req.path().to_owned();
+
+    // the body is read ahead to fingerprint the request and put back for the route
+    let mut payload = req.take_payload();
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        body.extend_from_slice(&chunk?);
+        if body.len() > crate::JSON_LIMIT {
+            return Err(ErrorPayloadTooLarge("payload too large"));
+        }
+    }
+    let body = body.freeze();
+
+    let mut fingerprint = format!("{} {}\n", method, path).into_bytes();
+    fingerprint.extend_from_slice(&body);
+
+    req.set_payload(Payload::Stream {
+        payload: stream::once(async move { Ok(body) }).boxed_local(),
+    });
+
+    let claim =
+        crate::queries::claim_idempotency_key(&email, &key, &method, &path, &fingerprint, &pool)
+            .await
+            .map_err(handle_unknown_error)?;
+
+    let stored = match claim {
+        IdempotencyClaim::Claimed => None,
+        IdempotencyClaim::Stored(stored) => Some(stored),
+        IdempotencyClaim::Released => {
+            return Err(ErrorConflict(format!(
+                "a request with the same {} is still in progress",
+                IDEMPOTENCY_KEY
+            )))
+        }
+        IdempotencyClaim::UnknownUser => return Err(ErrorNotFound("user not found")),
+    };
+
+    if let Some(stored) = stored {
+        if !stored.matches {
+            return Err(ErrorUnprocessableEntity(format!(
+                "{} already used for another request",
+                IDEMPOTENCY_KEY
+            )));
+        }
+
+        let Some(status) = stored.status else {
+            return Err(ErrorConflict(format!(
+                "a request with the same {} is still in progress",
+                IDEMPOTENCY_KEY
+            )));
+        };
+
+        let mut response =
+            HttpResponse::build(StatusCode::from_u16(status as u16).unwrap_or(StatusCode::OK));
+        if let Some(content_type) = stored.content_type {
+            response.content_type(content_type);
+        }
+        let response = response
+            .insert_header((IDEMPOTENT_REPLAYED, "true"))
+            .body(stored.body.unwrap_or_default());
+
+        return Ok(req.into_response(response));
+    }
+
+    let res = match next.call(req).await {
+        Ok(res) => res,
+        Err(e) => {
+            release(&email, &key, &pool).await;
+            return Err(e);
+        }
+    };
+
+    // server errors are not stored so that retries get another chance
+    if res.status().is_server_error() {
+        release(&email, &key, &pool).await;
+        return Ok(res.map_into_boxed_body());
+    }
+
+    let (req, res) = res.into_parts();
+    let (res, body) = res.into_parts();
+    let body = match to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => {
+            release(&email, &key, &pool).await;
+            let e: Box<dyn std::error::Error> = e.into();
+            return Err(ErrorInternalServerError(e.to_string()));
+        }
+    };
+
+    let content_type = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+
+    crate::queries::complete_idempotency_key(
+        &email,
+        &key,
+        res.status().as_u16() as i16,
+        content_type,
+        &body,
+        &pool,
+    )
+    .await
+    .map_err(handle_unknown_error)?;
+
+    Ok(ServiceResponse::new(req, res.set_body(body)).map_into_boxed_body())
+}
+
+async fn release(email: &str, key: &str, pool: &DbPool) {
+    if let Err(e) = crate::queries::release_idempotency_key(email, key, pool).await {
+        eprintln!("db error:\n{}", e);
+    }
+}
+
+fn handle_unknown_error(e: sqlx::Error) -> Error {
+    let error = format!("db error:\n{}", e);
+    eprintln!("{}", &error);
+    ErrorInternalServerError(error)
+}
@@ -0,0 +1,273 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+use crate::models::{Currency, Expense, SplitStrategy, User, UserId};
+
+/// Category Splitwise uses for the payments between users.
+const PAYMENT_CATEGORY: &str = "Payment";
+
+/// Max difference allowed when reconciling the balances of a row.
+const TOLERANCE: f64 = 0.005;
+
+#[derive(Deserialize)]
+pub struct SplitwiseImport {
+    pub csv: String,
+    /// names of the CSV columns to ids of the members, for the ones not matching any member
+    #[serde(default)]
+    pub mapping: HashMap<String, UserId>,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Serialize)]
+pub struct ImportError {
+    pub line: u64,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub imported: bool,
+    /// names in the CSV that could not be matched with a member and need a mapping
+    pub unknown_names: Vec<String>,
+    pub errors: Vec<ImportError>,
+    pub expenses: Vec<Expense>,
+}
+
+impl ImportReport {
+    pub fn is_valid(&self) -> bool {
+        self.unknown_names.is_empty() && self.errors.is_empty()
+    }
+
+    fn error(dry_run: bool, line: u64, message: String) -> Self {
+        Self {
+            dry_run,
+            imported: false,
+            unknown_names: Vec::default(),
+            errors: vec![ImportError { line, message }],
+            expenses: Vec::default(),
+        }
+    }
+}
+
+/// Parses a Splitwise CSV export into expenses of the group, each person column holds what they
+/// paid minus their share of the expense.
+pub fn parse_splitwise(
+    import: &SplitwiseImport,
+    members: &[User],
+    currencies: &[Currency],
+) -> ImportReport {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(import.csv.as_bytes());
+
+    let headers = match reader.headers() {
+        Ok(headers) => headers.clone(),
+        Err(e) => return ImportReport::error(import.dry_run, 1, e.to_string()),
+    };
+
+    let column = |name: &str| headers.iter().position(|h| h == name);
+    let (Some(date_i), Some(description_i), Some(category_i), Some(cost_i), Some(currency_i)) = (
+        column("Date"),
+        column("Description"),
+        column("Category"),
+        column("Cost"),
+        column("Currency"),
+    ) else {
+        return ImportReport::error(
+            import.dry_run,
+            1,
+            "missing Splitwise columns (Date, Description, Category, Cost, Currency)".to_owned(),
+        );
+    };
+    let fixed = [date_i, description_i, category_i, cost_i, currency_i];
+
+    // only members of the group can take part in the imported expenses
+    let mut not_members = import
+        .mapping
+        .iter()
+        .filter(|(_, user_id)| !members.iter().any(|u| &u.id == *user_id))
+        .map(|(name, user_id)| ImportError {
+            line: 1,
+            message: format!(
+                "`{}` is mapped to `{}`, not a member of the group",
+                name, user_id
+            ),
+        })
+        .collect::<Vec<_>>();
+
+    if !not_members.is_empty() {
+        not_members.sort_by(|a, b| a.message.cmp(&b.message));
+
+        return ImportReport {
+            dry_run: import.dry_run,
+            imported: false,
+            unknown_names: Vec::default(),
+            errors: not_members,
+            expenses: Vec::default(),
+        };
+    }
+
+    // resolve the person columns into members
+    let mut people = Vec::<(usize, UserId)>::default();
+    let mut unknown_names = Vec::default();
+    for (i, name) in headers.iter().enumerate() {
+        if fixed.contains(&i) || name.is_empty() {
+            continue;
+        }
+
+        let user_id = import.mapping.get(name).cloned().or_else(|| {
+            members
+                .iter()
+                .find(|u| {
+                    u.email.eq_ignore_ascii_case(name)
+                        || u.name
+                            .as_deref()
+                            .is_some_and(|n| n.eq_ignore_ascii_case(name))
+                })
+                .map(|u| u.id.clone())
+        });
+
+        match user_id {
+            Some(user_id) => people.push((i, user_id)),
+            None => unknown_names.push(name.to_owned()),
+        }
+    }
+
+    let mut report = ImportReport {
+        dry_run: import.dry_run,
+        imported: false,
+        unknown_names,
+        errors: Vec::default(),
+        expenses: Vec::default(),
+    };
+
+    if !report.unknown_names.is_empty() {
+        return report;
+    }
+
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                let line = e.position().map(|p| p.line()).unwrap_or_default();
+                report.errors.push(ImportError {
+                    line,
+                    message: e.to_string(),
+                });
+                continue;
+            }
+        };
+        let line = record.position().map(|p| p.line()).unwrap_or_default();
+        let field = |i: usize| record.get(i).unwrap_or_default();
+
+        // empty rows and the trailing total balance row have no date
+        if field(date_i).is_empty() {
+            continue;
+        }
+
+        let row = parse_row(
+            field(date_i),
+            field(description_i),
+            field(category_i),
+            field(cost_i),
+            field(currency_i),
+            people.iter().map(|(i, user_id)| (user_id, field(*i))),
+            currencies,
+        );
+
+        match row {
+            Ok(expense) => report.expenses.push(expense),
+            Err(message) => report.errors.push(ImportError { line, message }),
+        }
+    }
+
+    report
+}
+
+fn parse_row<'a>(
+    date: &str,
+    description: &str,
+    category: &str,
+    cost: &str,
+    currency: &str,
+    nets: impl Iterator<Item = (&'a UserId, &'a str)>,
+    currencies: &[Currency],
+) -> Result<Expense, String> {
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|e| format!("invalid date `{}`: {}", date, e))?;
+    let amount = cost
+        .parse::<f64>()
+        .map_err(|e| format!("invalid cost `{}`: {}", cost, e))?;
+    let currency_id = currencies
+        .iter()
+        .find(|c| c.acronym.eq_ignore_ascii_case(currency))
+        .map(|c| c.id)
+        .ok_or_else(|| format!("unknown currency `{}`", currency))?;
+
+    let mut payers = Vec::default();
+    let mut owers = Vec::default();
+    for (user_id, net) in nets {
+        let net = if net.is_empty() {
+            0f64
+        } else {
+            net.parse::<f64>()
+                .map_err(|e| format!("invalid balance `{}`: {}", net, e))?
+        };
+
+        if net > TOLERANCE {
+            payers.push((user_id.clone(), net));
+        } else if net < -TOLERANCE {
+            owers.push((user_id.clone(), -net));
+        }
+    }
+
+    let [(payer, _)] = payers.as_slice() else {
+        return Err("only expenses paid by a single person are supported".to_owned());
+    };
+    let payer = payer.clone();
+
+    let split_strategy = if category == PAYMENT_CATEGORY {
+        let [(recipient, _)] = owers.as_slice() else {
+            return Err("payments must have a single recipient".to_owned());
+        };
+
+        SplitStrategy::Payment {
+            payer,
+            recipient: recipient.clone(),
+        }
+    } else {
+        let mut shares = BTreeMap::from_iter(owers);
+
+        // the payer keeps whatever is not covered by the rest
+        let payer_share = amount - shares.values().sum::<f64>();
+        if payer_share > TOLERANCE {
+            shares.insert(payer.clone(), payer_share);
+        }
+
+        SplitStrategy::Exact { payer, shares }
+    };
+
+    split_strategy.validate(amount)?;
+
+    Ok(Expense {
+        id: None,
+        group_id: None,
+        deleted: false,
+        draft: false,
+        description: description.to_owned(),
+        currency_id,
+        amount,
+        date: date.and_hms_opt(0, 0, 0).expect("midnight").and_utc(),
+        split_strategy,
+        category: (!category.is_empty()).then(|| category.to_owned()),
+        created_by_id: None,
+        created_at: None,
+        updated_by_id: None,
+        updated_at: None,
+    })
+}
@@ -1,27 +1,38 @@
 use std::env;
+use std::sync::Arc;
 use std::thread::available_parallelism;
 
 use actix_cors::Cors;
+use actix_web::middleware::Logger;
 use actix_web::rt::spawn;
-use actix_web::web::Data;
-use actix_web::{middleware::Logger, App, HttpServer};
+use actix_web::web::{Data, JsonConfig};
+use actix_web::{http::header, App, HttpServer};
 use env_logger::Env;
-use futures::executor::block_on;
-use tokio::task::spawn_blocking;
 
 use ::auth::identity::IdentityService;
 
+use crate::bus::{Bus, InMemoryBus};
 use crate::queries::create_connection_pool;
-use crate::redis::create_redis_pool;
+use crate::redis::RedisBus;
 use crate::workers::activity::activity_detector;
 use crate::workers::sync::topics_sync;
 
+mod balances;
+mod bus;
+mod commands;
+mod export;
+mod idempotency;
+mod import;
 mod models;
 mod queries;
 mod redis;
 mod routes;
+mod statements;
+mod stats;
 mod workers;
 
+const JSON_LIMIT: usize = 8 * 1024 * 1024;
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     // Get the port number to listen on.
@@ -34,8 +45,36 @@ async fn main() -> std::io::Result<()> {
 
     let db_connection = create_connection_pool(&connspec).await.unwrap();
 
-    let connspec = env::var("REDIS_URI").expect("REDIS_URI");
-    let redis_pool = create_redis_pool(&connspec).await.expect("redis pool");
+    // maintenance commands run instead of the server
+    match env::args().nth(1).as_deref() {
+        Some("rebuild-balances") => {
+            commands::rebuild_balances(&db_connection)
+                .await
+                .expect("rebuild balances");
+            return Ok(());
+        }
+        Some("check-balances") => {
+            let consistent = commands::check_balances(&db_connection)
+                .await
+                .expect("check balances");
+            std::process::exit(if consistent { 0 } else { 1 });
+        }
+        Some(command) => panic!("unknown command `{command}`"),
+        None => {}
+    }
+
+    commands::rebuild_pending_balances(&db_connection)
+        .await
+        .expect("rebuild pending balances");
+
+    // the in-process bus runs a single replica without redis
+    let bus: Bus = match env::var("EVENT_BUS").as_deref() {
+        Ok("memory") => Arc::new(InMemoryBus::default()),
+        _ => {
+            let connspec = env::var("REDIS_URI").expect("REDIS_URI");
+            Arc::new(RedisBus::connect(&connspec).await.expect("redis bus"))
+        }
+    };
 
     let port = env::var("PORT")
         .unwrap_or_else(|_| "9000".to_string())
@@ -49,9 +88,8 @@ async fn main() -> std::io::Result<()> {
 
     println!("Starting server on {host}:{port}");
 
-    let db2 = db_connection.clone();
-    spawn(activity_detector());
-    spawn_blocking(move || block_on(topics_sync(db2.clone())));
+    spawn(activity_detector(bus.clone()));
+    spawn(topics_sync(db_connection.clone(), bus.clone()));
 
     let workers_num = available_parallelism().unwrap().get() * 2;
 
@@ -64,11 +102,14 @@ async fn main() -> std::io::Result<()> {
                     .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
                     .supports_credentials()
                     .allow_any_header()
+                    .expose_headers(vec![header::ETAG])
                     .allow_any_origin()
                     .max_age(3600),
             )
+            // imports carry whole files in their payloads
+            .app_data(JsonConfig::default().limit(JSON_LIMIT))
             .app_data(Data::new(db_connection.clone()))
-            .app_data(Data::new(redis_pool.clone()))
+            .app_data(Data::new(bus.clone()))
             .service(routes::status::status)
             .service(routes::auth::auth)
             .service(routes::auth::login)
@@ -88,7 +129,28 @@ async fn main() -> std::io::Result<()> {
             .service(routes::groups::delete_expense)
             .service(routes::groups::fetch_expenses)
             .service(routes::groups::fetch_balances)
+            .service(routes::groups::fetch_balance_history)
+            .service(routes::groups::fetch_stats)
+            .service(routes::groups::create_budget)
+            .service(routes::groups::fetch_budgets)
+            .service(routes::groups::delete_budget)
+            .service(routes::groups::export_group)
+            .service(routes::groups::import_splitwise)
+            .service(routes::groups::import_statement)
+            .service(routes::groups::confirm_drafts)
+            .service(routes::groups::publish_expense)
+            .service(routes::groups::update_expense)
+            .service(routes::groups::fetch_expense)
+            .service(routes::groups::create_expenses)
+            .service(routes::groups::apply_mutations)
+            .service(routes::groups::create_direct_expense)
+            .service(routes::groups::fetch_direct_expenses)
+            .service(routes::groups::fetch_friend_balances)
+            .service(routes::groups::fetch_my_balances)
             .service(routes::groups::sync)
+            .service(routes::groups::fetch_changes)
+            .service(routes::events::event_stream)
+            .service(routes::events::websocket)
     })
     .workers(workers_num)
     .bind((host, port))
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use serde::{Deserialize, Serialize};
 
@@ -8,6 +8,7 @@ pub type GroupId = i32;
 pub type UserId = String;
 pub type CurrencyId = i32;
 pub type ExpenseId = i32;
+pub type BudgetId = i32;
 
 #[derive(Clone, Debug, PartialEq, PartialOrd, sqlx::Type, Deserialize, Serialize)]
 #[sqlx(type_name = "notification_status", rename_all = "snake_case")]
@@ -36,6 +37,15 @@ pub enum UserStatus {
     Inactive,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, sqlx::Type, Deserialize, Serialize)]
+#[sqlx(type_name = "budget_period", rename_all = "snake_case")]
+#[serde(rename_all(serialize = "snake_case", deserialize = "snake_case"))]
+pub enum BudgetPeriod {
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
 #[derive(Serialize, Deserialize, sqlx::FromRow, Debug, Clone)]
 pub struct User {
     pub id: UserId,
@@ -65,8 +75,20 @@ impl From<User> for IdentityToken {
 #[serde(tag = "kind")]
 #[serde(rename_all(serialize = "snake_case", deserialize = "snake_case"))]
 pub enum NotificationKind {
-    Invite { group_id: GroupId },
-    Payment { expense_id: ExpenseId },
+    Invite {
+        group_id: GroupId,
+    },
+    Payment {
+        expense_id: ExpenseId,
+    },
+    DirectExpense {
+        expense_id: ExpenseId,
+    },
+    BudgetAlert {
+        group_id: GroupId,
+        budget_id: BudgetId,
+        consumed: f64,
+    },
 }
 
 impl From<serde_json::Value> for NotificationKind {
@@ -155,6 +177,15 @@ pub struct Membership {
     pub status_updated_at: chrono::DateTime<chrono::Utc>,
 }
 
+/// Membership of a user in a group, as seen by the rest of the members.
+#[derive(Serialize, Deserialize, sqlx::FromRow)]
+pub struct GroupMembership {
+    pub group_id: GroupId,
+    pub user_id: UserId,
+    pub status: MembershipStatus,
+    pub status_updated_at: chrono::DateTime<chrono::Utc>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Balance {
     pub user_id: UserId,
@@ -162,6 +193,324 @@ pub struct Balance {
     pub owes: HashMap<UserId, HashMap<CurrencyId, f64>>,
 }
 
+#[derive(Deserialize)]
+pub struct BalancesQuery {
+    /// only expenses up to this date are considered
+    pub as_of: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all(serialize = "snake_case", deserialize = "snake_case"))]
+pub enum HistoryBucket {
+    #[default]
+    Day,
+    Week,
+    Month,
+}
+
+/// Result of updates conditioned to the version of the resource known by the client.
+pub enum Versioned<T> {
+    Updated(T),
+    NotFound,
+    /// someone else updated the resource in the meantime, to the given version
+    Modified(chrono::DateTime<chrono::Utc>),
+}
+
+/// Outcome of claiming an idempotency key for a request.
+pub enum IdempotencyClaim {
+    /// the key is new, the request goes through
+    Claimed,
+    /// the key was claimed before, by the stored request
+    Stored(IdempotentResponse),
+    /// the key was released by its request in the meantime
+    Released,
+    UnknownUser,
+}
+
+/// Response stored for an idempotency key, empty while the original request is in progress.
+#[derive(sqlx::FromRow)]
+pub struct IdempotentResponse {
+    /// whether the stored request has the same method, path and body
+    pub matches: bool,
+    pub status: Option<i16>,
+    pub content_type: Option<String>,
+    pub body: Option<Vec<u8>>,
+}
+
+/// Outcome of each of the expenses of a batch, in the same order they were sent.
+#[derive(Serialize)]
+pub struct BatchItemResult {
+    pub id: Option<ExpenseId>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all(serialize = "snake_case", deserialize = "snake_case"))]
+pub enum ExpenseStatus {
+    #[default]
+    Published,
+    Draft,
+}
+
+/// Cursor of the log of events of the user.
+#[derive(Deserialize)]
+pub struct SyncQuery {
+    pub since: Option<String>,
+    /// device of the refresh token, resumes from the last cursor of the device when `since` is
+    /// missing
+    pub device_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ChangesQuery {
+    /// cursor returned by the previous call, everything is returned when missing
+    #[serde(default)]
+    pub since: i64,
+}
+
+/// Rows created or updated after the cursor, for clients to reconcile their local copies.
+#[derive(Serialize)]
+pub struct Changes {
+    pub cursor: i64,
+    pub groups: Vec<Group>,
+    pub memberships: Vec<GroupMembership>,
+    pub expenses: Vec<Expense>,
+    pub deleted_expenses: Vec<ExpenseId>,
+    pub notifications: Vec<Notification>,
+}
+
+/// Change queued by a client while offline, identified by an id generated by the client.
+#[derive(Deserialize)]
+pub struct Mutation {
+    pub id: uuid::Uuid,
+    #[serde(flatten)]
+    pub change: MutationKind,
+}
+
+/// Expense known by its id, or by the id of the mutation that created it for expenses created
+/// while offline.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(untagged)]
+pub enum ExpenseRef {
+    Id(ExpenseId),
+    Created(uuid::Uuid),
+}
+
+/// Versions are the `updated_at` (or `status_updated_at`) the client saw last, updates of
+/// expenses created in the same batch may omit them.
+#[derive(Deserialize)]
+#[serde(rename_all(serialize = "snake_case", deserialize = "snake_case"))]
+#[serde(tag = "kind")]
+pub enum MutationKind {
+    CreateExpense {
+        group_id: GroupId,
+        expense: Expense,
+    },
+    UpdateExpense {
+        group_id: GroupId,
+        expense_id: ExpenseRef,
+        base_version: Option<chrono::DateTime<chrono::Utc>>,
+        expense: Expense,
+    },
+    DeleteExpense {
+        group_id: GroupId,
+        expense_id: ExpenseRef,
+        base_version: Option<chrono::DateTime<chrono::Utc>>,
+    },
+    UpdateMembership {
+        group_id: GroupId,
+        status: MembershipStatus,
+        base_version: chrono::DateTime<chrono::Utc>,
+    },
+    UpdateNotification {
+        notification_id: i32,
+        status: NotificationStatus,
+        base_version: chrono::DateTime<chrono::Utc>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all(serialize = "snake_case", deserialize = "snake_case"))]
+#[serde(tag = "status")]
+pub enum MutationOutcome {
+    Applied {
+        expense_id: Option<ExpenseId>,
+        version: chrono::DateTime<chrono::Utc>,
+    },
+    /// the resource changed since the base version and nothing was applied, the current version
+    /// is missing when the resource does not exist anymore
+    Conflict {
+        version: Option<chrono::DateTime<chrono::Utc>>,
+    },
+    Rejected {
+        error: String,
+    },
+}
+
+#[derive(Serialize)]
+pub struct MutationResult {
+    pub id: uuid::Uuid,
+    #[serde(flatten)]
+    pub outcome: MutationOutcome,
+}
+
+/// Changes delivered to the clients, carrying enough data to be applied without fetching the
+/// resources again.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all(serialize = "snake_case", deserialize = "snake_case"))]
+#[serde(tag = "kind")]
+pub enum Event {
+    ExpenseCreated {
+        expense: Expense,
+    },
+    /// batches and imports, too many to be sent whole
+    ExpensesCreated {
+        group_id: GroupId,
+        expense_ids: Vec<ExpenseId>,
+    },
+    ExpenseUpdated {
+        expense: Expense,
+    },
+    ExpensesPublished {
+        group_id: GroupId,
+        expense_ids: Vec<ExpenseId>,
+    },
+    ExpenseDeleted {
+        group_id: GroupId,
+        expense_id: ExpenseId,
+    },
+    MemberJoined {
+        group_id: GroupId,
+        user: User,
+    },
+    MemberLeft {
+        group_id: GroupId,
+        user: User,
+    },
+    GroupConfigChanged {
+        group: Group,
+    },
+    BudgetsChanged {
+        group_id: GroupId,
+    },
+    Typing {
+        group_id: GroupId,
+        expense_id: Option<ExpenseId>,
+        email: String,
+    },
+    NotificationCreated {
+        id: i32,
+    },
+    /// the cursor is past the retention of the log, everything has to be fetched again
+    Reset,
+}
+
+/// What gets published to the topics, authors are not sent their own events.
+#[derive(Serialize, Deserialize)]
+pub struct EventPayload {
+    pub author: String,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// Recipient of a notification that was just created.
+pub struct CreatedNotification {
+    pub id: i32,
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ExpensesQuery {
+    #[serde(default)]
+    pub status: ExpenseStatus,
+}
+
+#[derive(Deserialize)]
+pub struct BalanceHistoryQuery {
+    #[serde(default)]
+    pub bucket: HistoryBucket,
+}
+
+/// Net total of each member at the end of a bucket of time starting at `date`.
+#[derive(Serialize, Deserialize)]
+pub struct BalanceSnapshot {
+    pub date: chrono::DateTime<chrono::Utc>,
+    pub totals: HashMap<UserId, HashMap<CurrencyId, f64>>,
+}
+
+fn default_threshold() -> f64 {
+    1f64
+}
+
+#[derive(Serialize, Deserialize, sqlx::FromRow, Clone)]
+pub struct Budget {
+    pub id: Option<BudgetId>,
+    pub group_id: Option<GroupId>,
+
+    pub amount: f64,
+    pub currency_id: CurrencyId,
+    pub period: BudgetPeriod,
+    /// only expenses in this category count against the budget when present
+    pub category: Option<String>,
+    /// fraction of the amount that triggers the alerts
+    #[serde(default = "default_threshold")]
+    pub threshold: f64,
+
+    pub created_by_id: Option<UserId>,
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Spend consumed from a budget in the period starting at `period_start`.
+#[derive(Serialize, Deserialize)]
+pub struct BudgetStatus {
+    #[serde(flatten)]
+    pub budget: Budget,
+    pub period_start: chrono::DateTime<chrono::Utc>,
+    pub consumed: f64,
+}
+
+#[derive(Deserialize)]
+pub struct StatsQuery {
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    pub to: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct MemberStats {
+    pub paid: HashMap<CurrencyId, f64>,
+    pub consumed: HashMap<CurrencyId, f64>,
+}
+
+/// Spending of a group, payments between members are settlements and not accounted as spending.
+#[derive(Default, Serialize, Deserialize)]
+pub struct GroupStats {
+    pub total: HashMap<CurrencyId, f64>,
+    pub members: HashMap<UserId, MemberStats>,
+    pub categories: HashMap<String, HashMap<CurrencyId, f64>>,
+    /// keyed by `YYYY-MM`
+    pub months: BTreeMap<String, HashMap<CurrencyId, f64>>,
+}
+
+/// Materialized balance between two members of a group for a given currency.
+#[derive(Serialize, Deserialize, sqlx::FromRow)]
+pub struct PairBalance {
+    pub group_id: GroupId,
+    pub user_id: UserId,
+    pub other_user_id: UserId,
+    pub currency_id: CurrencyId,
+    pub amount: f64,
+}
+
+/// What the current user owes to (positive) or is owed by (negative) another user across groups
+/// and direct expenses.
+#[derive(Serialize, Deserialize)]
+pub struct FriendBalance {
+    pub user_id: UserId,
+    pub total: HashMap<CurrencyId, f64>,
+    pub groups: HashMap<GroupId, HashMap<CurrencyId, f64>>,
+    pub direct: HashMap<CurrencyId, f64>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MembershipInvitation {
     pub emails: Vec<String>,
@@ -174,7 +523,7 @@ pub struct Currency {
     pub description: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Expense {
     pub id: Option<ExpenseId>,
     pub group_id: Option<GroupId>,
@@ -182,12 +531,17 @@ pub struct Expense {
     #[serde(skip_serializing)]
     #[serde(skip_deserializing)]
     pub deleted: bool,
+    /// drafts are not taken into account for balances until published
+    #[serde(default)]
+    pub draft: bool,
 
     pub description: String,
     pub currency_id: i32,
     pub amount: f64,
     pub date: chrono::DateTime<chrono::Utc>,
     pub split_strategy: SplitStrategy,
+    #[serde(default)]
+    pub category: Option<String>,
 
     pub created_by_id: Option<UserId>,
     pub created_at: Option<chrono::DateTime<chrono::Utc>>,
@@ -208,6 +562,212 @@ pub enum SplitStrategy {
         payer: UserId,
         recipient: UserId,
     },
+    Itemized {
+        payer: UserId,
+        items: Vec<ExpenseItem>,
+        #[serde(default)]
+        tax: f64,
+        #[serde(default)]
+        tip: f64,
+    },
+    Adjusted {
+        payer: UserId,
+        split_between: Vec<UserId>,
+        /// signed amounts added on top of the equal part of each user
+        adjustments: BTreeMap<UserId, f64>,
+    },
+    Exact {
+        payer: UserId,
+        shares: BTreeMap<UserId, f64>,
+    },
+}
+
+/// Max difference allowed when reconciling the parts of a split against the expense amount.
+const AMOUNT_TOLERANCE: f64 = 0.005;
+
+impl SplitStrategy {
+    pub fn payer(&self) -> &UserId {
+        match self {
+            SplitStrategy::Equally { payer, .. }
+            | SplitStrategy::Payment { payer, .. }
+            | SplitStrategy::Itemized { payer, .. }
+            | SplitStrategy::Adjusted { payer, .. }
+            | SplitStrategy::Exact { payer, .. } => payer,
+        }
+    }
+
+    /// Users involved in the expense, starting with the payer.
+    pub fn participants(&self) -> Vec<UserId> {
+        let mut participants = vec![self.payer().clone()];
+        for (user, _) in self.shares(0f64) {
+            if !participants.contains(&user) {
+                participants.push(user);
+            }
+        }
+        participants
+    }
+
+    /// Resolves the strategy into the share each participant takes from an expense of `amount`,
+    /// the payer might be included among them.
+    pub fn shares(&self, amount: f64) -> Vec<(UserId, f64)> {
+        match self {
+            SplitStrategy::Equally { split_between, .. } => {
+                let roman = amount / split_between.len() as f64;
+
+                split_between
+                    .iter()
+                    .map(|ower| (ower.clone(), roman))
+                    .collect()
+            }
+            SplitStrategy::Payment { recipient, .. } => vec![(recipient.clone(), amount)],
+            SplitStrategy::Itemized {
+                items, tax, tip, ..
+            } => {
+                let mut subtotals = Vec::<(UserId, f64)>::default();
+                for item in items {
+                    let roman = item.total() / item.split_between.len() as f64;
+
+                    for ower in &item.split_between {
+                        match subtotals.iter_mut().find(|(user, _)| user == ower) {
+                            Some((_, subtotal)) => *subtotal += roman,
+                            None => subtotals.push((ower.clone(), roman)),
+                        }
+                    }
+                }
+
+                let subtotal = items.iter().map(ExpenseItem::total).sum::<f64>();
+                if subtotal > 0f64 {
+                    // tax and tip are distributed proportionally to what each one consumed
+                    let factor = 1f64 + (tax + tip) / subtotal;
+
+                    subtotals
+                        .into_iter()
+                        .map(|(ower, subtotal)| (ower, subtotal * factor))
+                        .collect()
+                } else {
+                    // nothing to be proportional to, tax and tip are split equally instead
+                    let roman = (tax + tip) / subtotals.len() as f64;
+
+                    subtotals
+                        .into_iter()
+                        .map(|(ower, subtotal)| (ower, subtotal + roman))
+                        .collect()
+                }
+            }
+            SplitStrategy::Adjusted {
+                split_between,
+                adjustments,
+                ..
+            } => {
+                // adjustments are taken out of the amount before splitting the rest equally
+                let adjusted = adjustments.values().sum::<f64>();
+                let roman = (amount - adjusted) / split_between.len() as f64;
+
+                split_between
+                    .iter()
+                    .map(|ower| {
+                        let adjustment = adjustments.get(ower).copied().unwrap_or_default();
+                        (ower.clone(), roman + adjustment)
+                    })
+                    .collect()
+            }
+            SplitStrategy::Exact { shares, .. } => shares
+                .iter()
+                .map(|(ower, share)| (ower.clone(), *share))
+                .collect(),
+        }
+    }
+
+    pub fn validate(&self, amount: f64) -> Result<(), String> {
+        match self {
+            SplitStrategy::Equally { .. } | SplitStrategy::Payment { .. } => Ok(()),
+            SplitStrategy::Itemized {
+                items, tax, tip, ..
+            } => {
+                if items.is_empty() {
+                    return Err("itemized expense without items".to_owned());
+                }
+
+                if *tax < 0f64 || *tip < 0f64 {
+                    return Err("tax and tip cannot be negative".to_owned());
+                }
+
+                for item in items {
+                    if item.price < 0f64 || item.quantity == 0 {
+                        return Err(format!(
+                            "invalid price or quantity for item `{}`",
+                            item.name
+                        ));
+                    }
+
+                    if item.split_between.is_empty() {
+                        return Err(format!("item `{}` is not assigned to anyone", item.name));
+                    }
+                }
+
+                let total = items.iter().map(ExpenseItem::total).sum::<f64>() + tax + tip;
+                if (total - amount).abs() > AMOUNT_TOLERANCE {
+                    return Err(format!(
+                        "items plus tax and tip add up to {} instead of {}",
+                        total, amount
+                    ));
+                }
+
+                Ok(())
+            }
+            SplitStrategy::Adjusted {
+                split_between,
+                adjustments,
+                ..
+            } => {
+                if split_between.is_empty() {
+                    return Err("adjusted expense not split between anyone".to_owned());
+                }
+
+                if let Some(user) = adjustments.keys().find(|u| !split_between.contains(u)) {
+                    return Err(format!("adjustment for `{}` who is not in the split", user));
+                }
+
+                // shares add up to the amount by construction, but none of them can go negative
+                let shares = self.shares(amount);
+                if shares.iter().any(|(_, share)| *share < -AMOUNT_TOLERANCE) {
+                    return Err("adjustments exceed the expense amount".to_owned());
+                }
+
+                Ok(())
+            }
+            SplitStrategy::Exact { shares, .. } => {
+                if shares.is_empty() {
+                    return Err("exact expense without shares".to_owned());
+                }
+
+                if shares.values().any(|share| *share < 0f64) {
+                    return Err("shares cannot be negative".to_owned());
+                }
+
+                let total = shares.values().sum::<f64>();
+                if (total - amount).abs() > AMOUNT_TOLERANCE {
+                    return Err(format!("shares add up to {} instead of {}", total, amount));
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, PartialOrd, Deserialize, Serialize)]
+pub struct ExpenseItem {
+    pub name: String,
+    pub price: f64,
+    pub quantity: u32,
+    pub split_between: Vec<UserId>,
+}
+
+impl ExpenseItem {
+    pub fn total(&self) -> f64 {
+        self.price * self.quantity as f64
+    }
 }
 
 impl From<serde_json::Value> for SplitStrategy {
@@ -226,3 +786,171 @@ pub struct NotificationsUpdate {
 pub struct NotificationUpdate {
     pub status: NotificationStatus,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(name: &str, price: f64, quantity: u32, split_between: &[&str]) -> ExpenseItem {
+        ExpenseItem {
+            name: name.to_owned(),
+            price,
+            quantity,
+            split_between: split_between.iter().map(|u| u.to_string()).collect(),
+        }
+    }
+
+    fn itemized(items: Vec<ExpenseItem>, tax: f64, tip: f64) -> SplitStrategy {
+        SplitStrategy::Itemized {
+            payer: "ana".to_owned(),
+            items,
+            tax,
+            tip,
+        }
+    }
+
+    fn share_of(shares: &[(UserId, f64)], user: &str) -> f64 {
+        shares
+            .iter()
+            .filter(|(u, _)| u == user)
+            .map(|(_, share)| *share)
+            .sum()
+    }
+
+    fn assert_reconciles(strategy: &SplitStrategy, amount: f64) {
+        let total = strategy
+            .shares(amount)
+            .iter()
+            .map(|(_, share)| share)
+            .sum::<f64>();
+        assert!(
+            (total - amount).abs() <= AMOUNT_TOLERANCE,
+            "shares add up to {} instead of {}",
+            total,
+            amount
+        );
+    }
+
+    #[test]
+    fn itemized_distributes_tax_and_tip_proportionally() {
+        let strategy = itemized(
+            vec![
+                item("steak", 30f64, 1, &["ana"]),
+                item("salad", 10f64, 1, &["bob"]),
+            ],
+            4f64,
+            6f64,
+        );
+
+        assert_eq!(strategy.validate(50f64), Ok(()));
+
+        let shares = strategy.shares(50f64);
+        assert!((share_of(&shares, "ana") - 37.5).abs() < 1e-9);
+        assert!((share_of(&shares, "bob") - 12.5).abs() < 1e-9);
+        assert_reconciles(&strategy, 50f64);
+    }
+
+    #[test]
+    fn itemized_splits_shared_items() {
+        let strategy = itemized(
+            vec![
+                item("wine", 15f64, 2, &["ana", "bob", "cid"]),
+                item("pasta", 12f64, 1, &["cid"]),
+            ],
+            0f64,
+            0f64,
+        );
+
+        assert_eq!(strategy.validate(42f64), Ok(()));
+
+        let shares = strategy.shares(42f64);
+        assert!((share_of(&shares, "ana") - 10f64).abs() < 1e-9);
+        assert!((share_of(&shares, "bob") - 10f64).abs() < 1e-9);
+        assert!((share_of(&shares, "cid") - 22f64).abs() < 1e-9);
+        assert_reconciles(&strategy, 42f64);
+    }
+
+    #[test]
+    fn itemized_splits_tax_and_tip_equally_for_free_items() {
+        let strategy = itemized(
+            vec![item("water", 0f64, 3, &["ana", "bob", "cid"])],
+            0f64,
+            9f64,
+        );
+
+        assert_eq!(strategy.validate(9f64), Ok(()));
+
+        let shares = strategy.shares(9f64);
+        for user in ["ana", "bob", "cid"] {
+            assert!((share_of(&shares, user) - 3f64).abs() < 1e-9);
+        }
+        assert_reconciles(&strategy, 9f64);
+    }
+
+    #[test]
+    fn itemized_rejects_amounts_that_do_not_match() {
+        let strategy = itemized(vec![item("steak", 30f64, 1, &["ana"])], 3f64, 0f64);
+
+        assert!(strategy.validate(30f64).is_err());
+        assert!(strategy.validate(33.1).is_err());
+        assert_eq!(strategy.validate(33.001), Ok(()));
+    }
+
+    #[test]
+    fn itemized_rejects_invalid_items() {
+        let empty = itemized(vec![], 0f64, 0f64);
+        assert!(empty.validate(0f64).is_err());
+
+        let unassigned = itemized(vec![item("steak", 30f64, 1, &[])], 0f64, 0f64);
+        assert!(unassigned.validate(30f64).is_err());
+
+        let negative_tip = itemized(vec![item("steak", 30f64, 1, &["ana"])], 0f64, -1f64);
+        assert!(negative_tip.validate(29f64).is_err());
+    }
+
+    fn adjusted(split_between: &[&str], adjustments: &[(&str, f64)]) -> SplitStrategy {
+        SplitStrategy::Adjusted {
+            payer: "ana".to_owned(),
+            split_between: split_between.iter().map(|u| u.to_string()).collect(),
+            adjustments: adjustments
+                .iter()
+                .map(|(u, adjustment)| (u.to_string(), *adjustment))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn adjusted_takes_adjustments_out_before_splitting() {
+        let strategy = adjusted(&["ana", "bob", "cid"], &[("ana", 10f64), ("cid", -1f64)]);
+
+        assert_eq!(strategy.validate(60f64), Ok(()));
+
+        let shares = strategy.shares(60f64);
+        assert!((share_of(&shares, "ana") - 27f64).abs() < 1e-9);
+        assert!((share_of(&shares, "bob") - 17f64).abs() < 1e-9);
+        assert!((share_of(&shares, "cid") - 16f64).abs() < 1e-9);
+        assert_reconciles(&strategy, 60f64);
+    }
+
+    #[test]
+    fn adjusted_rejects_negative_shares() {
+        let strategy = adjusted(&["ana", "bob"], &[("ana", 30f64)]);
+
+        assert!(strategy.validate(20f64).is_err());
+        assert_eq!(strategy.validate(30f64), Ok(()));
+    }
+
+    #[test]
+    fn adjusted_rejects_adjustments_outside_the_split() {
+        let strategy = adjusted(&["ana", "bob"], &[("cid", 5f64)]);
+
+        assert!(strategy.validate(20f64).is_err());
+    }
+
+    #[test]
+    fn adjusted_rejects_empty_splits() {
+        let strategy = adjusted(&[], &[]);
+
+        assert!(strategy.validate(20f64).is_err());
+    }
+}
@@ -1,10 +1,13 @@
 use std::collections::{HashMap, HashSet};
 
 use chrono::NaiveDateTime;
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
 use serde::{Deserialize, Serialize};
-use sqlx::{postgres::PgPoolOptions, PgPool};
+use sqlx::{postgres::PgPoolOptions, Acquire, PgConnection, PgExecutor, PgPool, Postgres};
 use uuid::Uuid;
 
+use crate::balances::{pair_changes, PairKey};
 use crate::models::{self, DetailedGroup, Expense, GroupId, SplitStrategy};
 
 pub type DbPool = PgPool;
@@ -25,7 +28,8 @@ pub async fn upsert_user(
          ON CONFLICT (email) DO UPDATE
          SET name = $3,
              picture = $4,
-             status = $5
+             status = $5,
+             updated_at = CURRENT_TIMESTAMP
          RETURNING id"#,
         user.id,
         user.email,
@@ -77,7 +81,7 @@ pub async fn find_groups(email: &str, pool: &DbPool) -> Result<Vec<models::Group
 
 pub async fn find_memberships(
     group_id: models::GroupId,
-    pool: &DbPool,
+    executor: impl PgExecutor<'_>,
 ) -> Result<Vec<models::InternalMembership>, sqlx::Error> {
     sqlx::query_as!(
         models::InternalMembership,
@@ -88,7 +92,7 @@ pub async fn find_memberships(
          ORDER BY m.user_id",
         group_id
     )
-    .fetch_all(pool)
+    .fetch_all(executor)
     .await
 }
 
@@ -162,29 +166,53 @@ pub async fn find_group(
     })
 }
 
+/// Updates the group when it is still in one of the `expected` versions (any when `None`),
+/// returns the new version.
 pub async fn update_group(
     _email: &str,
     group_id: models::GroupId,
     group: models::Group,
+    expected: Option<Vec<chrono::DateTime<chrono::Utc>>>,
     pool: &DbPool,
-) -> Result<(), sqlx::Error> {
-    // create group
+) -> Result<models::Versioned<chrono::DateTime<chrono::Utc>>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let current = sqlx::query!(
+        "SELECT updated_at
+         FROM groups
+         WHERE id = $1
+         FOR UPDATE",
+        group_id,
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(current) = current else {
+        return Ok(models::Versioned::NotFound);
+    };
+
+    if expected.is_some_and(|e| !e.contains(&current.updated_at)) {
+        return Ok(models::Versioned::Modified(current.updated_at));
+    }
+
     let value: serde_json::Value = group.balance_config.into();
 
-    sqlx::query!(
+    let updated = sqlx::query!(
         "UPDATE groups
-       SET name = $1, default_currency_id = $2, balance_config = $3
-       WHERE id = $4",
+       SET name = $1, default_currency_id = $2, balance_config = $3, updated_at = CURRENT_TIMESTAMP
+       WHERE id = $4
+       RETURNING updated_at",
         group.name,
         group.default_currency_id,
         value,
         group_id,
     )
-    .execute(pool)
+    .fetch_one(&mut *tx)
     .await?;
 
-    // TODO - get the id from the new group - moliva - 2024/03/10
-    Ok(())
+    tx.commit().await?;
+
+    Ok(models::Versioned::Updated(updated.updated_at))
 }
 
 pub async fn create_group(
@@ -230,7 +258,7 @@ pub async fn update_notifications(
 ) -> Result<(), sqlx::Error> {
     sqlx::query!(
         r#"UPDATE notifications
-         SET status = $2
+         SET status = $2, status_updated_at = CURRENT_TIMESTAMP
          WHERE id IN (SELECT * FROM UNNEST($1::integer[]))
          "#,
         &update.ids,
@@ -242,45 +270,102 @@ pub async fn update_notifications(
     Ok(())
 }
 
+/// Updates the status of the notification of the user when it is still in one of the `expected`
+/// versions (any when `None`).
 pub async fn update_notification(
+    email: &str,
     notification_id: i32,
     update: models::NotificationUpdate,
-    pool: &DbPool,
-) -> Result<(), sqlx::Error> {
-    sqlx::query!(
+    expected: Option<Vec<chrono::DateTime<chrono::Utc>>>,
+    conn: impl Acquire<'_, Database = Postgres>,
+) -> Result<models::Versioned<chrono::DateTime<chrono::Utc>>, sqlx::Error> {
+    let mut tx = conn.begin().await?;
+
+    let current = sqlx::query!(
+        "SELECT n.status_updated_at
+         FROM notifications n, users u
+         WHERE n.id = $1
+         AND n.user_id = u.id AND u.email = $2
+         FOR UPDATE OF n",
+        notification_id,
+        email,
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(current) = current else {
+        return Ok(models::Versioned::NotFound);
+    };
+
+    if expected.is_some_and(|e| !e.contains(&current.status_updated_at)) {
+        return Ok(models::Versioned::Modified(current.status_updated_at));
+    }
+
+    let updated = sqlx::query!(
         r#"UPDATE notifications
-         SET status = $2
+         SET status = $2, status_updated_at = CURRENT_TIMESTAMP
          WHERE id = $1
+         RETURNING status_updated_at
          "#,
         notification_id,
         update.status as models::NotificationStatus
     )
-    .execute(pool)
+    .fetch_one(&mut *tx)
     .await?;
 
-    Ok(())
+    tx.commit().await?;
+
+    Ok(models::Versioned::Updated(updated.status_updated_at))
 }
 
+/// Updates the status of the membership of the user when it is still in one of the `expected`
+/// versions (any when `None`).
 pub async fn update_membership(
     email: &str,
     status: models::MembershipStatus,
     group: models::GroupId,
-    pool: &DbPool,
-) -> Result<(), sqlx::Error> {
-    sqlx::query!(
+    expected: Option<Vec<chrono::DateTime<chrono::Utc>>>,
+    conn: impl Acquire<'_, Database = Postgres>,
+) -> Result<models::Versioned<chrono::DateTime<chrono::Utc>>, sqlx::Error> {
+    let mut tx = conn.begin().await?;
+
+    let current = sqlx::query!(
+        "SELECT m.status_updated_at
+         FROM memberships m, users u
+         WHERE m.group_id = $2
+         AND m.user_id = u.id AND u.email = $1
+         FOR UPDATE OF m",
+        email,
+        group,
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(current) = current else {
+        return Ok(models::Versioned::NotFound);
+    };
+
+    if expected.is_some_and(|e| !e.contains(&current.status_updated_at)) {
+        return Ok(models::Versioned::Modified(current.status_updated_at));
+    }
+
+    let updated = sqlx::query!(
         r#"UPDATE memberships
-         SET status = $3
+         SET status = $3, status_updated_at = CURRENT_TIMESTAMP
          WHERE group_id = $2
          AND user_id = (SELECT id FROM users WHERE email = $1 LIMIT 1)
+         RETURNING status_updated_at
          "#,
         email,
         group,
         status as models::MembershipStatus,
     )
-    .execute(pool)
+    .fetch_one(&mut *tx)
     .await?;
 
-    Ok(())
+    tx.commit().await?;
+
+    Ok(models::Versioned::Updated(updated.status_updated_at))
 }
 
 pub async fn create_membership_invites(
@@ -288,7 +373,7 @@ pub async fn create_membership_invites(
     emails: &Vec<String>,
     group_id: i32,
     pool: &DbPool,
-) -> Result<(), sqlx::Error> {
+) -> Result<Vec<models::CreatedNotification>, sqlx::Error> {
     let existing = sqlx::query!(
         "SELECT u.email, u.id
          FROM users u
@@ -341,19 +426,205 @@ pub async fn create_membership_invites(
     let invite = models::NotificationKind::Invite { group_id };
     let invite = serde_json::to_value(invite).expect("serialized value");
 
-    sqlx::query!(
-        r#"INSERT INTO notifications (user_id, data)
-           SELECT i, $2
-           FROM UNNEST($1::text[]) as t (i)
+    let notifications = sqlx::query_as!(
+        models::CreatedNotification,
+        r#"WITH inserted AS (
+             INSERT INTO notifications (user_id, data)
+             SELECT i, $2
+             FROM UNNEST($1::text[]) as t (i)
+             RETURNING id, user_id
+           )
+           SELECT n.id AS "id!", u.email AS "email!"
+           FROM inserted n, users u
+           WHERE u.id = n.user_id
          "#,
         &all_ids,
         invite,
     )
-    .execute(pool)
+    .fetch_all(pool)
     .await
     .expect("insert notifications");
 
-    Ok(())
+    Ok(notifications)
+}
+
+/// Groups, memberships, expenses and notifications of the user that changed after the cursor.
+/// Groups the user just joined are sent whole, along with their members and expenses.
+pub async fn find_changes(
+    email: &str,
+    since: i64,
+    pool: &DbPool,
+) -> Result<models::Changes, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    // all the queries see the same snapshot, changes of the transactions in flight when it was
+    // taken may or may not be part of it and are sent again next time
+    sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ")
+        .execute(&mut *tx)
+        .await?;
+
+    let snapshot = sqlx::query!(
+        r#"SELECT pg_snapshot_xmin(pg_current_snapshot())::text::bigint AS "cursor!",
+                  pg_snapshot_xmax(pg_current_snapshot())::text::bigint AS "next!"
+        "#
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    let cursor = snapshot.cursor;
+
+    // cursors from ahead of every transaction so far were not handed out by this database
+    let since = if since > snapshot.next { 0 } else { since };
+
+    let groups = sqlx::query_as!(
+        models::Group,
+        r#"WITH me AS (SELECT id FROM users WHERE email = $1)
+           SELECT g.*
+           FROM groups g
+           WHERE g.id IN (
+             SELECT m.group_id FROM memberships m, me
+             WHERE m.user_id = me.id AND m.status = 'joined'
+           )
+           AND g.id IN (
+             SELECT c.group_id FROM changes c, me
+             WHERE c.txid >= $2::bigint::text::xid8
+             AND (c.kind = 'group' OR (c.kind = 'membership' AND c.user_id = me.id))
+           )
+           ORDER BY g.id"#,
+        email,
+        since,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let memberships = sqlx::query_as!(
+        models::GroupMembership,
+        r#"WITH me AS (SELECT id FROM users WHERE email = $1),
+           joined AS (
+             SELECT m.group_id FROM memberships m, me
+             WHERE m.user_id = me.id AND m.status = 'joined'
+           ),
+           renewed AS (
+             SELECT c.group_id FROM changes c, me
+             WHERE c.txid >= $2::bigint::text::xid8
+             AND c.kind = 'membership' AND c.user_id = me.id
+           )
+           SELECT m.group_id, m.user_id, m.status AS "status!: models::MembershipStatus", m.status_updated_at
+           FROM memberships m
+           WHERE (
+             m.group_id IN (SELECT group_id FROM renewed)
+             AND (m.user_id = (SELECT id FROM me) OR m.group_id IN (SELECT group_id FROM joined))
+           )
+           OR (
+             m.group_id IN (SELECT group_id FROM joined)
+             AND (m.group_id, m.user_id) IN (
+               SELECT c.group_id, c.user_id FROM changes c
+               WHERE c.txid >= $2::bigint::text::xid8
+               AND c.kind = 'membership'
+             )
+           )
+           ORDER BY m.group_id, m.user_id"#,
+        email,
+        since,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let expenses = sqlx::query_as!(
+        models::Expense,
+        r#"WITH me AS (SELECT id FROM users WHERE email = $1),
+           changed AS (
+             SELECT c.expense_id FROM changes c
+             WHERE c.txid >= $2::bigint::text::xid8
+             AND c.kind = 'expense'
+           )
+           SELECT e.*
+           FROM expenses e
+           WHERE (
+             e.group_id IN (
+               SELECT m.group_id FROM memberships m, me
+               WHERE m.user_id = me.id AND m.status = 'joined'
+             )
+             AND (
+               e.id IN (SELECT expense_id FROM changed)
+               OR e.group_id IN (
+                 SELECT c.group_id FROM changes c, me
+                 WHERE c.txid >= $2::bigint::text::xid8
+                 AND c.kind = 'membership' AND c.user_id = me.id
+               )
+             )
+           )
+           OR (
+             e.group_id IS NULL
+             AND e.id IN (SELECT expense_id FROM changed)
+             AND e.id IN (
+               SELECT p.expense_id FROM expense_participants p, me
+               WHERE p.user_id = me.id
+             )
+           )
+           ORDER BY e.id"#,
+        email,
+        since,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let notifications = sqlx::query_as!(
+        models::Notification,
+        r#"SELECT n.id, n.status AS "status!: models::NotificationStatus", n.user_id, n.data, n.status_updated_at, n.created_at
+           FROM users u, notifications n
+           WHERE n.user_id = u.id AND u.email = $1
+           AND n.id IN (
+             SELECT c.notification_id FROM changes c
+             WHERE c.txid >= $2::bigint::text::xid8
+             AND c.kind = 'notification'
+           )
+           ORDER BY n.id"#,
+        email,
+        since,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    // deleted expenses only matter to clients that knew about them already
+    let (deleted, expenses): (Vec<_>, Vec<_>) = expenses.into_iter().partition(|e| e.deleted);
+
+    Ok(models::Changes {
+        cursor,
+        groups,
+        memberships,
+        expenses,
+        deleted_expenses: deleted.into_iter().filter_map(|e| e.id).collect(),
+        notifications,
+    })
+}
+
+/// Whether the user is a joined member of the group, and the expense (if any) belongs to it.
+pub async fn can_type(
+    email: &str,
+    group_id: GroupId,
+    expense_id: Option<models::ExpenseId>,
+    pool: &DbPool,
+) -> Result<bool, sqlx::Error> {
+    let record = sqlx::query!(
+        r#"SELECT EXISTS (
+             SELECT 1 FROM memberships m, users u
+             WHERE u.email = $1 AND m.user_id = u.id
+             AND m.group_id = $2 AND m.status = 'joined'
+           )
+           AND ($3::integer IS NULL OR EXISTS (
+             SELECT 1 FROM expenses e
+             WHERE e.id = $3 AND e.group_id = $2
+           )) AS "allowed!""#,
+        email,
+        group_id,
+        expense_id,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(record.allowed)
 }
 
 pub async fn find_notifications(
@@ -384,26 +655,49 @@ pub async fn find_notifications(
         groups.into_iter().map(|g| (g.id.expect("group id"), g)),
     );
 
-    // fetch all payments
-    let payment_ids = notifications
+    // fetch all payments and direct expenses
+    let expense_ids = notifications
         .iter()
         .filter_map(|n| match n.data {
-            models::NotificationKind::Payment { expense_id } => Some(expense_id),
+            models::NotificationKind::Payment { expense_id }
+            | models::NotificationKind::DirectExpense { expense_id } => Some(expense_id),
             _ => None,
         })
         .collect::<Vec<_>>();
 
-    let payments = sqlx::query_as!(
+    let expenses = sqlx::query_as!(
         models::Expense,
         "SELECT e.*
          FROM expenses e, (SELECT * FROM UNNEST($1::integer[])) as t(i)
          WHERE e.id = t.i",
-        &payment_ids,
+        &expense_ids,
+    )
+    .fetch_all(pool)
+    .await?;
+    let expenses = HashMap::<i32, models::Expense>::from_iter(
+        expenses.into_iter().map(|e| (e.id.expect("id"), e)),
+    );
+
+    // fetch all budgets
+    let budget_ids = notifications
+        .iter()
+        .filter_map(|n| match n.data {
+            models::NotificationKind::BudgetAlert { budget_id, .. } => Some(budget_id),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let budgets = sqlx::query_as!(
+        models::Budget,
+        r#"SELECT b.id, b.group_id, b.amount, b.currency_id, b.period AS "period!: models::BudgetPeriod", b.category, b.threshold, b.created_by_id, b.created_at
+         FROM budgets b
+         WHERE b.id = ANY($1)"#,
+        &budget_ids,
     )
     .fetch_all(pool)
     .await?;
-    let payments = HashMap::<i32, models::Expense>::from_iter(
-        payments.into_iter().map(|e| (e.id.expect("id"), e)),
+    let budgets = HashMap::<models::BudgetId, models::Budget>::from_iter(
+        budgets.into_iter().map(|b| (b.id.expect("id"), b)),
     );
 
     // fetch all memberships
@@ -450,12 +744,12 @@ pub async fn find_notifications(
                         split_strategy,
                         created_by_id,
                         ..
-                    } = payments.get(&expense_id).unwrap();
+                    } = expenses.get(&expense_id).unwrap();
 
                     match split_strategy {
                         SplitStrategy::Payment { payer, recipient } => {
                             NotificationDtoKind::Payment {
-                                group: groups.get(&group_id.unwrap()).unwrap().clone(),
+                                group: group_id.map(|id| groups.get(&id).unwrap().clone()),
                                 currency_id: *currency_id,
                                 amount: *amount,
                                 date: *date,
@@ -470,6 +764,36 @@ pub async fn find_notifications(
                         _ => panic!("expected payment"),
                     }
                 }
+                models::NotificationKind::BudgetAlert {
+                    group_id,
+                    budget_id,
+                    consumed,
+                } => NotificationDtoKind::BudgetAlert {
+                    group: groups.get(&group_id).unwrap().clone(),
+                    budget: budgets.get(&budget_id).unwrap().clone(),
+                    consumed,
+                },
+                models::NotificationKind::DirectExpense { expense_id } => {
+                    let Expense {
+                        description,
+                        currency_id,
+                        amount,
+                        date,
+                        split_strategy,
+                        created_by_id,
+                        ..
+                    } = expenses.get(&expense_id).unwrap();
+
+                    NotificationDtoKind::DirectExpense {
+                        expense_id,
+                        description: description.clone(),
+                        currency_id: *currency_id,
+                        amount: *amount,
+                        date: *date,
+                        split_strategy: split_strategy.clone(),
+                        created_by: users.get(&created_by_id.clone().unwrap()).unwrap().clone(),
+                    }
+                }
             },
             id: n.id,
             user_id: n.user_id,
@@ -488,34 +812,283 @@ pub async fn find_currencies(pool: &DbPool) -> Result<Vec<models::Currency>, sql
         .await
 }
 
+/// Soft deletes the expense when it is still in one of the `expected` versions (any when `None`),
+/// returns the version of the deletion.
 pub async fn delete_expense(
-    _email: &str,
-    _group_id: GroupId,
+    email: &str,
+    group_id: GroupId,
     expense_id: i32,
-    pool: &DbPool,
-) -> Result<(), sqlx::Error> {
-    sqlx::query!(
-        r#"UPDATE expenses 
-        SET deleted = true
-        WHERE id = $1"#,
-        expense_id
+    expected: Option<Vec<chrono::DateTime<chrono::Utc>>>,
+    conn: impl Acquire<'_, Database = Postgres>,
+) -> Result<models::Versioned<chrono::DateTime<chrono::Utc>>, sqlx::Error> {
+    let mut tx = conn.begin().await?;
+
+    let expense = sqlx::query_as!(
+        models::Expense,
+        r#"SELECT *
+           FROM expenses
+           WHERE id = $1
+           AND group_id = $2
+           AND deleted = false
+           FOR UPDATE"#,
+        expense_id,
+        group_id,
     )
-    .execute(pool)
+    .fetch_optional(&mut *tx)
     .await?;
 
-    Ok(())
+    let Some(expense) = expense else {
+        return Ok(models::Versioned::NotFound);
+    };
+
+    let version = expense.updated_at.expect("expense version");
+    if expected.is_some_and(|e| !e.contains(&version)) {
+        return Ok(models::Versioned::Modified(version));
+    }
+
+    let deleted = sqlx::query!(
+        r#"UPDATE expenses e
+        SET deleted = true, updated_by_id = u.id, updated_at = CURRENT_TIMESTAMP
+        FROM users u
+        WHERE u.email = $2
+        AND e.id = $1
+        RETURNING e.updated_at"#,
+        expense_id,
+        email,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if !expense.draft {
+        // revert what the expense added to the balances
+        let changes = pair_changes([&expense])
+            .into_iter()
+            .map(|(key, amount)| (key, -amount))
+            .collect();
+        apply_pair_changes(changes, &mut tx).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(models::Versioned::Updated(deleted.updated_at))
 }
 
 pub async fn create_expense(
+    email: &str,
+    group_id: Option<GroupId>,
+    expense: Expense,
+    conn: impl Acquire<'_, Database = Postgres>,
+) -> Result<(i32, Vec<models::CreatedNotification>), sqlx::Error> {
+    let mut tx = conn.begin().await?;
+
+    let expense_id = insert_expense(email, group_id, &expense, &mut tx).await?;
+
+    // drafts are notified once published
+    let mut notifications = Vec::default();
+    if !expense.draft {
+        notifications =
+            insert_payment_notification(email, expense_id, &expense.split_strategy, &mut tx)
+                .await?;
+    }
+
+    if let Some(group_id) = group_id {
+        notifications
+            .extend(insert_budget_alerts(group_id, std::slice::from_ref(&expense), &mut tx).await?);
+    }
+
+    tx.commit().await?;
+
+    Ok((expense_id, notifications))
+}
+
+async fn insert_payment_notification(
+    email: &str,
+    expense_id: models::ExpenseId,
+    split_strategy: &SplitStrategy,
+    conn: &mut PgConnection,
+) -> Result<Vec<models::CreatedNotification>, sqlx::Error> {
+    let SplitStrategy::Payment { payer, recipient } = split_strategy else {
+        return Ok(Vec::default());
+    };
+
+    let payment = models::NotificationKind::Payment { expense_id };
+    let payment = serde_json::to_value(payment).expect("serialized value");
+
+    sqlx::query_as!(
+        models::CreatedNotification,
+        r#"WITH inserted AS (
+             INSERT INTO notifications (user_id, data)
+             SELECT CASE WHEN u.id = $3 THEN $4 ELSE $3 END, $2
+             FROM users u
+             WHERE u.email = $1
+             RETURNING id, user_id
+           )
+           SELECT n.id AS "id!", u.email AS "email!"
+           FROM inserted n, users u
+           WHERE u.id = n.user_id
+         "#,
+        email,
+        payment,
+        payer,
+        recipient
+    )
+    .fetch_all(&mut *conn)
+    .await
+}
+
+/// Replaces the data of the expense keeping its draft status when it is still in one of the
+/// `expected` versions (any when `None`), the balances of the group get updated with the
+/// difference.
+pub async fn update_expense(
     email: &str,
     group_id: GroupId,
+    expense_id: models::ExpenseId,
     expense: Expense,
+    expected: Option<Vec<chrono::DateTime<chrono::Utc>>>,
+    conn: impl Acquire<'_, Database = Postgres>,
+) -> Result<models::Versioned<models::Expense>, sqlx::Error> {
+    let mut tx = conn.begin().await?;
+
+    let previous = sqlx::query_as!(
+        models::Expense,
+        r#"SELECT *
+           FROM expenses
+           WHERE id = $1
+           AND group_id = $2
+           AND deleted = false
+           FOR UPDATE"#,
+        expense_id,
+        group_id,
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(previous) = previous else {
+        return Ok(models::Versioned::NotFound);
+    };
+
+    let version = previous.updated_at.expect("expense version");
+    if expected.is_some_and(|e| !e.contains(&version)) {
+        return Ok(models::Versioned::Modified(version));
+    }
+
+    let serialized_value = serde_json::to_value(&expense.split_strategy).expect("serialized value");
+
+    let updated = sqlx::query_as!(
+        models::Expense,
+        r#"UPDATE expenses e
+           SET description = $3, currency_id = $4, amount = $5, date = $6, split_strategy = $7,
+               category = $8, updated_by_id = u.id, updated_at = CURRENT_TIMESTAMP
+           FROM users u
+           WHERE u.email = $1
+           AND e.id = $2
+           RETURNING e.*"#,
+        email,
+        expense_id,
+        expense.description,
+        expense.currency_id,
+        expense.amount,
+        expense.date,
+        serialized_value,
+        expense.category,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if !previous.draft {
+        let mut changes = pair_changes([&updated]);
+        for (key, amount) in pair_changes([&previous]) {
+            *changes.entry(key).or_default() -= amount;
+        }
+        apply_pair_changes(changes, &mut tx).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(models::Versioned::Updated(updated))
+}
+
+pub async fn find_expense(
+    group_id: GroupId,
+    expense_id: models::ExpenseId,
+    executor: impl PgExecutor<'_>,
+) -> Result<Option<models::Expense>, sqlx::Error> {
+    sqlx::query_as!(
+        models::Expense,
+        r#"SELECT *
+           FROM expenses
+           WHERE id = $1
+           AND group_id = $2
+           AND deleted = false"#,
+        expense_id,
+        group_id,
+    )
+    .fetch_optional(executor)
+    .await
+}
+
+/// Creates all the expenses in a single transaction along with their payment notifications.
+pub async fn create_expenses(
+    email: &str,
+    group_id: GroupId,
+    expenses: &[Expense],
+    pool: &DbPool,
+) -> Result<(Vec<models::ExpenseId>, Vec<models::CreatedNotification>), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let mut ids = Vec::with_capacity(expenses.len());
+    let mut notifications = Vec::default();
+    for expense in expenses {
+        let expense_id = insert_expense(email, Some(group_id), expense, &mut tx).await?;
+
+        if !expense.draft {
+            notifications.extend(
+                insert_payment_notification(email, expense_id, &expense.split_strategy, &mut tx)
+                    .await?,
+            );
+        }
+
+        ids.push(expense_id);
+    }
+
+    notifications.extend(insert_budget_alerts(group_id, expenses, &mut tx).await?);
+
+    tx.commit().await?;
+
+    Ok((ids, notifications))
+}
+
+/// Creates all the expenses in a single transaction, no notifications are sent for them.
+pub async fn import_expenses(
+    email: &str,
+    group_id: GroupId,
+    expenses: &[Expense],
     pool: &DbPool,
-) -> Result<i32, sqlx::Error> {
+) -> Result<Vec<models::ExpenseId>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let mut ids = Vec::with_capacity(expenses.len());
+    for expense in expenses {
+        ids.push(insert_expense(email, Some(group_id), expense, &mut tx).await?);
+    }
+
+    tx.commit().await?;
+
+    Ok(ids)
+}
+
+/// Inserts the expense and updates the balances of its group accordingly.
+async fn insert_expense(
+    email: &str,
+    group_id: Option<GroupId>,
+    expense: &Expense,
+    conn: &mut PgConnection,
+) -> Result<models::ExpenseId, sqlx::Error> {
     let serialized_value = serde_json::to_value(&expense.split_strategy).expect("serialized value");
+
     let r = sqlx::query!(
-        r#"INSERT INTO expenses (created_by_id, updated_by_id, group_id, description, currency_id, amount, date, split_strategy)
-           SELECT                u.id,          u.id,          $2,       $3,          $4,          $5,     $6,   $7
+        r#"INSERT INTO expenses (created_by_id, updated_by_id, group_id, description, currency_id, amount, date, split_strategy, category, draft)
+           SELECT                u.id,          u.id,          $2,       $3,          $4,          $5,     $6,   $7,             $8,       $9
            FROM users u
            WHERE u.email = $1
            LIMIT 1
@@ -528,49 +1101,349 @@ pub async fn create_expense(
         expense.amount,
         expense.date,
         serialized_value,
+        expense.category,
+        expense.draft,
     )
-    .fetch_one(pool)
+    .fetch_one(&mut *conn)
     .await?;
 
-    if let SplitStrategy::Payment { payer, recipient } = expense.split_strategy {
-        let expense_id = r.id;
+    // drafts don't count towards the balances until they get published
+    if !expense.draft {
+        let changes = pair_changes([&Expense {
+            group_id,
+            ..expense.clone()
+        }]);
+        apply_pair_changes(changes, conn).await?;
+    }
 
-        let payment = models::NotificationKind::Payment { expense_id };
-        let payment = serde_json::to_value(payment).expect("serialized value");
+    Ok(r.id)
+}
 
-        sqlx::query!(
-            r#"INSERT INTO notifications (user_id, data)
-           SELECT CASE WHEN u.id = $3 THEN $4 ELSE $3 END, $2
-           FROM users u
-           WHERE u.email = $1
+pub async fn create_direct_expense(
+    email: &str,
+    expense: Expense,
+    pool: &DbPool,
+) -> Result<(i32, Vec<models::CreatedNotification>), sqlx::Error> {
+    let participants = expense.split_strategy.participants();
+    let is_payment = matches!(expense.split_strategy, SplitStrategy::Payment { .. });
+
+    let mut tx = pool.begin().await?;
+
+    let expense_id = insert_expense(email, None, &expense, &mut tx).await?;
+
+    let mut notifications =
+        insert_payment_notification(email, expense_id, &expense.split_strategy, &mut tx).await?;
+
+    sqlx::query!(
+        r#"INSERT INTO expense_participants (expense_id, user_id)
+           SELECT $1, i
+           FROM UNNEST($2::text[]) as t (i)
          "#,
+        expense_id,
+        &participants,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if !is_payment {
+        // payments are already notified on creation, the rest of the participants get notified here
+        let direct_expense = models::NotificationKind::DirectExpense { expense_id };
+        let direct_expense = serde_json::to_value(direct_expense).expect("serialized value");
+
+        let direct = sqlx::query_as!(
+            models::CreatedNotification,
+            r#"WITH inserted AS (
+                 INSERT INTO notifications (user_id, data)
+                 SELECT i, $3
+                 FROM UNNEST($2::text[]) as t (i), users u
+                 WHERE u.email = $1 AND u.id != i
+                 RETURNING id, user_id
+               )
+               SELECT n.id AS "id!", u.email AS "email!"
+               FROM inserted n, users u
+               WHERE u.id = n.user_id
+             "#,
             email,
-            payment,
-            payer,
-            recipient
+            &participants,
+            direct_expense,
         )
-        .execute(pool)
-        .await
-        .expect("insert notifications");
+        .fetch_all(&mut *tx)
+        .await?;
+
+        notifications.extend(direct);
     }
 
-    Ok(r.id)
+    tx.commit().await?;
+
+    Ok((expense_id, notifications))
 }
 
-// TODO - paging and have `date` as separate to group easily - moliva - 2024/03/21
-pub async fn find_expenses(
-    _email: &str,
-    group_id: GroupId,
+pub async fn find_direct_expenses(
+    email: &str,
     pool: &DbPool,
 ) -> Result<Vec<models::Expense>, sqlx::Error> {
-    let expenses = sqlx::query_as!(
+    sqlx::query_as!(
         models::Expense,
-        r#"SELECT *
-           FROM expenses
+        r#"SELECT e.*
+           FROM expenses e, expense_participants p, users u
+           WHERE e.id = p.expense_id
+           AND p.user_id = u.id AND u.email = $1
+           AND e.group_id IS NULL
+           AND e.deleted = false
+           AND e.draft = false
+           ORDER BY e.date DESC"#,
+        email
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn find_joined_expenses(
+    email: &str,
+    pool: &DbPool,
+) -> Result<Vec<models::Expense>, sqlx::Error> {
+    sqlx::query_as!(
+        models::Expense,
+        r#"SELECT e.*
+           FROM expenses e, memberships m, users u
+           WHERE e.group_id = m.group_id
+           AND m.user_id = u.id AND u.email = $1
+           AND m.status = 'joined'
+           AND e.deleted = false
+           AND e.draft = false
+           ORDER BY e.date DESC"#,
+        email
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn find_joined_memberships(
+    email: &str,
+    pool: &DbPool,
+) -> Result<Vec<models::InternalMembership>, sqlx::Error> {
+    sqlx::query_as!(
+        models::InternalMembership,
+        "SELECT m.user_id, m.group_id, m.created_by_id
+         FROM memberships m
+         WHERE m.status = 'joined'
+         AND m.group_id IN (
+           SELECT j.group_id
+           FROM memberships j, users u
+           WHERE j.user_id = u.id AND u.email = $1 AND j.status = 'joined'
+         )
+         ORDER BY m.group_id, m.user_id",
+        email
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn find_user(
+    email: &str,
+    executor: impl PgExecutor<'_>,
+) -> Result<models::User, sqlx::Error> {
+    sqlx::query_as!(
+        models::User,
+        r#"SELECT id, email, status AS "status!: models::UserStatus", name, picture, created_at, updated_at
+           FROM users
+           WHERE email = $1"#,
+        email
+    )
+    .fetch_one(executor)
+    .await
+}
+
+pub async fn find_users(
+    ids: &[models::UserId],
+    executor: impl PgExecutor<'_>,
+) -> Result<Vec<models::User>, sqlx::Error> {
+    sqlx::query_as!(
+        models::User,
+        r#"SELECT u.id, u.email, u.status AS "status!: models::UserStatus", u.name, u.picture, u.created_at, u.updated_at
+           FROM users u
+           WHERE u.id = ANY($1)
+           ORDER BY u.id"#,
+        ids
+    )
+    .fetch_all(executor)
+    .await
+}
+
+pub async fn find_pair_balances(
+    group_id: GroupId,
+    executor: impl PgExecutor<'_>,
+) -> Result<Vec<models::PairBalance>, sqlx::Error> {
+    sqlx::query_as!(
+        models::PairBalance,
+        r#"SELECT b.group_id, b.user_id, b.other_user_id, b.currency_id, b.amount
+           FROM balances b
+           WHERE b.group_id = $1
+           ORDER BY b.user_id, b.other_user_id, b.currency_id"#,
+        group_id
+    )
+    .fetch_all(executor)
+    .await
+}
+
+pub async fn find_all_pair_balances(
+    pool: &DbPool,
+) -> Result<Vec<models::PairBalance>, sqlx::Error> {
+    sqlx::query_as!(
+        models::PairBalance,
+        r#"SELECT b.group_id, b.user_id, b.other_user_id, b.currency_id, b.amount
+           FROM balances b
+           ORDER BY b.group_id, b.user_id, b.other_user_id, b.currency_id"#,
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn find_all_group_expenses(pool: &DbPool) -> Result<Vec<models::Expense>, sqlx::Error> {
+    sqlx::query_as!(
+        models::Expense,
+        r#"SELECT *
+           FROM expenses
+           WHERE group_id IS NOT NULL
+           AND deleted = false
+           AND draft = false
+           ORDER BY group_id, date"#,
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn find_all_memberships(
+    pool: &DbPool,
+) -> Result<Vec<models::InternalMembership>, sqlx::Error> {
+    sqlx::query_as!(
+        models::InternalMembership,
+        "SELECT m.user_id, m.group_id, m.created_by_id
+         FROM memberships m
+         WHERE m.status = 'joined'
+         ORDER BY m.group_id, m.user_id",
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Recomputes the materialized balances of every group from the expenses log.
+pub async fn rebuild_pair_balances(pool: &DbPool) -> Result<usize, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let count = rebuild_pairs(&mut tx).await?;
+
+    tx.commit().await?;
+
+    Ok(count)
+}
+
+/// Rebuilds the balances when the task is pending, returns the balances rebuilt if it was. The
+/// task is taken along with the rebuild, replicas starting at the same time wait for it.
+pub async fn rebuild_pending_pair_balances(pool: &DbPool) -> Result<Option<usize>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let pending = sqlx::query!(
+        r#"DELETE FROM pending_tasks
+           WHERE name = 'rebuild-balances'
+           RETURNING name"#
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if pending.is_none() {
+        return Ok(None);
+    }
+
+    let count = rebuild_pairs(&mut tx).await?;
+
+    tx.commit().await?;
+
+    Ok(Some(count))
+}
+
+async fn rebuild_pairs(conn: &mut PgConnection) -> Result<usize, sqlx::Error> {
+    // no expenses can come in while the balances are being rebuilt
+    sqlx::query!("LOCK TABLE expenses IN SHARE MODE")
+        .execute(&mut *conn)
+        .await?;
+
+    sqlx::query!("DELETE FROM balances")
+        .execute(&mut *conn)
+        .await?;
+
+    let expenses = sqlx::query_as!(
+        models::Expense,
+        r#"SELECT *
+           FROM expenses
+           WHERE group_id IS NOT NULL
+           AND deleted = false
+           AND draft = false"#,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let changes = pair_changes(&expenses);
+    let count = changes.len();
+    apply_pair_changes(changes, conn).await?;
+
+    Ok(count)
+}
+
+async fn apply_pair_changes(
+    changes: HashMap<PairKey, f64>,
+    conn: &mut PgConnection,
+) -> Result<(), sqlx::Error> {
+    let mut group_ids = Vec::with_capacity(changes.len());
+    let mut user_ids = Vec::with_capacity(changes.len());
+    let mut other_user_ids = Vec::with_capacity(changes.len());
+    let mut currency_ids = Vec::with_capacity(changes.len());
+    let mut amounts = Vec::with_capacity(changes.len());
+
+    for ((group_id, user_id, other_user_id, currency_id), amount) in changes {
+        group_ids.push(group_id);
+        user_ids.push(user_id);
+        other_user_ids.push(other_user_id);
+        currency_ids.push(currency_id);
+        amounts.push(amount);
+    }
+
+    sqlx::query!(
+        r#"INSERT INTO balances (group_id, user_id, other_user_id, currency_id, amount)
+           SELECT *
+           FROM UNNEST($1::integer[], $2::text[], $3::text[], $4::integer[], $5::float8[])
+           ON CONFLICT (group_id, user_id, other_user_id, currency_id)
+           DO UPDATE SET amount = balances.amount + EXCLUDED.amount
+         "#,
+        &group_ids,
+        &user_ids,
+        &other_user_ids,
+        &currency_ids,
+        &amounts,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+// TODO - paging and have `date` as separate to group easily - moliva - 2024/03/21
+pub async fn find_expenses(
+    _email: &str,
+    group_id: GroupId,
+    status: models::ExpenseStatus,
+    pool: &DbPool,
+) -> Result<Vec<models::Expense>, sqlx::Error> {
+    let expenses = sqlx::query_as!(
+        models::Expense,
+        r#"SELECT *
+           FROM expenses
            WHERE group_id = $1
            AND deleted = false
+           AND draft = $2
            ORDER BY date DESC"#,
-        group_id
+        group_id,
+        status == models::ExpenseStatus::Draft,
     )
     .fetch_all(pool)
     .await?;
@@ -578,6 +1451,582 @@ pub async fn find_expenses(
     Ok(expenses)
 }
 
+pub async fn find_expenses_as_of(
+    group_id: GroupId,
+    as_of: chrono::DateTime<chrono::Utc>,
+    pool: &DbPool,
+) -> Result<Vec<models::Expense>, sqlx::Error> {
+    sqlx::query_as!(
+        models::Expense,
+        r#"SELECT *
+           FROM expenses
+           WHERE group_id = $1
+           AND deleted = false
+           AND draft = false
+           AND date <= $2
+           ORDER BY date"#,
+        group_id,
+        as_of,
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Expenses of the group in order, read from the database as they are consumed.
+pub fn stream_ledger<'e>(
+    group_id: GroupId,
+    include_deleted: bool,
+    executor: impl PgExecutor<'e> + 'e,
+) -> BoxStream<'e, Result<models::Expense, sqlx::Error>> {
+    sqlx::query_as!(
+        models::Expense,
+        r#"SELECT *
+           FROM expenses
+           WHERE group_id = $1
+           AND (deleted = false OR $2)
+           AND draft = false
+           ORDER BY date, id"#,
+        group_id,
+        include_deleted,
+    )
+    .fetch(executor)
+}
+
+/// Every user found in the split of the expenses of the group, whichever the strategy.
+pub async fn find_ledger_participants(
+    group_id: GroupId,
+    conn: &mut PgConnection,
+) -> Result<HashSet<models::UserId>, sqlx::Error> {
+    let mut strategies = sqlx::query!(
+        r#"SELECT split_strategy
+           FROM expenses
+           WHERE group_id = $1
+           AND draft = false"#,
+        group_id,
+    )
+    .fetch(conn);
+
+    let mut participants = HashSet::new();
+    while let Some(row) = strategies.try_next().await? {
+        participants.extend(SplitStrategy::from(row.split_strategy).participants());
+    }
+
+    Ok(participants)
+}
+
+/// Starts a transaction where every query sees the same snapshot.
+pub async fn begin_snapshot(
+    pool: &DbPool,
+) -> Result<sqlx::Transaction<'static, sqlx::Postgres>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ")
+        .execute(&mut *tx)
+        .await?;
+
+    Ok(tx)
+}
+
+pub async fn find_expenses_between(
+    group_id: GroupId,
+    from: Option<chrono::DateTime<chrono::Utc>>,
+    to: Option<chrono::DateTime<chrono::Utc>>,
+    pool: &DbPool,
+) -> Result<Vec<models::Expense>, sqlx::Error> {
+    sqlx::query_as!(
+        models::Expense,
+        r#"SELECT *
+           FROM expenses
+           WHERE group_id = $1
+           AND deleted = false
+           AND draft = false
+           AND ($2::timestamptz IS NULL OR date >= $2)
+           AND ($3::timestamptz IS NULL OR date <= $3)
+           ORDER BY date"#,
+        group_id,
+        from,
+        to,
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Expenses of the group in the range including drafts, to find out what was already recorded.
+pub async fn find_recorded_expenses(
+    group_id: GroupId,
+    from: chrono::DateTime<chrono::Utc>,
+    to: chrono::DateTime<chrono::Utc>,
+    pool: &DbPool,
+) -> Result<Vec<models::Expense>, sqlx::Error> {
+    sqlx::query_as!(
+        models::Expense,
+        r#"SELECT *
+           FROM expenses
+           WHERE group_id = $1
+           AND deleted = false
+           AND date >= $2 AND date <= $3
+           ORDER BY date"#,
+        group_id,
+        from,
+        to,
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Turns the drafts into real expenses of the group, returns the ones that got published.
+pub async fn publish_drafts(
+    email: &str,
+    group_id: GroupId,
+    expense_ids: &[models::ExpenseId],
+    pool: &DbPool,
+) -> Result<(Vec<models::Expense>, Vec<models::CreatedNotification>), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let expenses = sqlx::query_as!(
+        models::Expense,
+        r#"UPDATE expenses e
+           SET draft = false, updated_by_id = u.id, updated_at = CURRENT_TIMESTAMP
+           FROM users u
+           WHERE u.email = $1
+           AND e.group_id = $2
+           AND e.id = ANY($3)
+           AND e.draft = true
+           AND e.deleted = false
+           RETURNING e.*"#,
+        email,
+        group_id,
+        expense_ids,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let changes = pair_changes(&expenses);
+    apply_pair_changes(changes, &mut tx).await?;
+
+    let mut notifications = Vec::default();
+    for expense in expenses.iter() {
+        let expense_id = expense.id.expect("expense id");
+        notifications.extend(
+            insert_payment_notification(email, expense_id, &expense.split_strategy, &mut tx)
+                .await?,
+        );
+    }
+
+    notifications.extend(insert_budget_alerts(group_id, &expenses, &mut tx).await?);
+
+    tx.commit().await?;
+
+    Ok((expenses, notifications))
+}
+
+/// Claims the key for a new request of the user, identified by its fingerprint, returns the
+/// stored response when the key was already claimed.
+pub async fn claim_idempotency_key(
+    email: &str,
+    key: &str,
+    method: &str,
+    path: &str,
+    fingerprint: &[u8],
+    pool: &DbPool,
+) -> Result<models::IdempotencyClaim, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let Some(user) = sqlx::query!(r#"SELECT id FROM users WHERE email = $1"#, email)
+        .fetch_optional(&mut *tx)
+        .await?
+    else {
+        return Ok(models::IdempotencyClaim::UnknownUser);
+    };
+
+    // expired keys can be reused
+    sqlx::query!(
+        r#"DELETE FROM idempotency_keys
+           WHERE user_id = $1
+           AND created_at < CURRENT_TIMESTAMP - INTERVAL '1 day'"#,
+        user.id,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let claimed = sqlx::query!(
+        r#"INSERT INTO idempotency_keys (user_id, key, method, path, fingerprint)
+           VALUES ($1, $2, $3, $4, sha256($5))
+           ON CONFLICT DO NOTHING
+           RETURNING key"#,
+        user.id,
+        key,
+        method,
+        path,
+        fingerprint,
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let claim = if claimed.is_some() {
+        models::IdempotencyClaim::Claimed
+    } else {
+        sqlx::query_as!(
+            models::IdempotentResponse,
+            r#"SELECT
+                 (method = $3 AND path = $4
+                   AND (fingerprint IS NULL OR fingerprint = sha256($5))) AS "matches!",
+                 status, content_type, body
+               FROM idempotency_keys
+               WHERE user_id = $1 AND key = $2"#,
+            user.id,
+            key,
+            method,
+            path,
+            fingerprint,
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .map_or(
+            models::IdempotencyClaim::Released,
+            models::IdempotencyClaim::Stored,
+        )
+    };
+
+    tx.commit().await?;
+
+    Ok(claim)
+}
+
+pub async fn complete_idempotency_key(
+    email: &str,
+    key: &str,
+    status: i16,
+    content_type: Option<&str>,
+    body: &[u8],
+    pool: &DbPool,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE idempotency_keys k
+           SET status = $3, content_type = $4, body = $5
+           FROM users u
+           WHERE u.email = $1 AND k.user_id = u.id
+           AND k.key = $2"#,
+        email,
+        key,
+        status,
+        content_type,
+        body,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Frees the key so that the request can be retried.
+pub async fn release_idempotency_key(
+    email: &str,
+    key: &str,
+    pool: &DbPool,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"DELETE FROM idempotency_keys k
+           USING users u
+           WHERE u.email = $1 AND k.user_id = u.id
+           AND k.key = $2"#,
+        email,
+        key,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Claims the mutation of the user to be applied within the transaction, returns `None` when
+/// claimed or the stored outcome when it was already applied. Claims of the same mutation running
+/// alongside wait for the transaction, and find the mutation unclaimed again if it rolls back.
+pub async fn claim_mutation(
+    email: &str,
+    mutation_id: Uuid,
+    conn: &mut PgConnection,
+) -> Result<Option<models::MutationOutcome>, sqlx::Error> {
+    // clients are not expected to stay offline for this long, claims without an outcome are left
+    // by versions that stored it after applying the mutation
+    sqlx::query!(
+        r#"DELETE FROM mutations m
+           USING users u
+           WHERE u.email = $1 AND m.user_id = u.id
+           AND (m.created_at < CURRENT_TIMESTAMP - INTERVAL '30 days' OR m.outcome IS NULL)"#,
+        email,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let claimed = sqlx::query!(
+        r#"INSERT INTO mutations (user_id, id)
+           SELECT u.id, $2
+           FROM users u
+           WHERE u.email = $1
+           ON CONFLICT DO NOTHING
+           RETURNING id"#,
+        email,
+        mutation_id,
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    if claimed.is_some() {
+        return Ok(None);
+    }
+
+    let stored = sqlx::query!(
+        r#"SELECT m.outcome AS "outcome!"
+           FROM mutations m, users u
+           WHERE u.email = $1 AND m.user_id = u.id
+           AND m.id = $2"#,
+        email,
+        mutation_id,
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    Ok(Some(
+        serde_json::from_value(stored.outcome).expect("deserialized outcome"),
+    ))
+}
+
+/// Outcome of a mutation of the user already applied.
+pub async fn find_mutation_outcome(
+    email: &str,
+    mutation_id: Uuid,
+    executor: impl PgExecutor<'_>,
+) -> Result<Option<models::MutationOutcome>, sqlx::Error> {
+    let stored = sqlx::query!(
+        r#"SELECT m.outcome
+           FROM mutations m, users u
+           WHERE u.email = $1 AND m.user_id = u.id
+           AND m.id = $2"#,
+        email,
+        mutation_id,
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(stored
+        .and_then(|s| s.outcome)
+        .map(|o| serde_json::from_value(o).expect("deserialized outcome")))
+}
+
+/// Stores the outcome of the mutation to be returned on retries, along with the changes it made.
+pub async fn complete_mutation(
+    email: &str,
+    mutation_id: Uuid,
+    outcome: &models::MutationOutcome,
+    executor: impl PgExecutor<'_>,
+) -> Result<(), sqlx::Error> {
+    let outcome = serde_json::to_value(outcome).expect("serialized outcome");
+
+    sqlx::query!(
+        r#"UPDATE mutations m
+           SET outcome = $3
+           FROM users u
+           WHERE u.email = $1 AND m.user_id = u.id
+           AND m.id = $2"#,
+        email,
+        mutation_id,
+        outcome,
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn create_budget(
+    email: &str,
+    group_id: GroupId,
+    budget: models::Budget,
+    pool: &DbPool,
+) -> Result<models::BudgetId, sqlx::Error> {
+    let r = sqlx::query!(
+        r#"INSERT INTO budgets (created_by_id, group_id, amount, currency_id, period, category, threshold)
+           SELECT                u.id,          $2,       $3,     $4,          $5,     $6,       $7
+           FROM users u
+           WHERE u.email = $1
+           LIMIT 1
+           RETURNING id
+         "#,
+        email,
+        group_id,
+        budget.amount,
+        budget.currency_id,
+        budget.period as models::BudgetPeriod,
+        budget.category,
+        budget.threshold,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(r.id)
+}
+
+pub async fn delete_budget(
+    group_id: GroupId,
+    budget_id: models::BudgetId,
+    pool: &DbPool,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE budgets
+        SET deleted = true
+        WHERE id = $1 AND group_id = $2"#,
+        budget_id,
+        group_id,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Budgets of a group along with the spend consumed in the period that contains `at`.
+pub async fn find_budget_statuses(
+    group_id: GroupId,
+    at: chrono::DateTime<chrono::Utc>,
+    executor: impl PgExecutor<'_>,
+) -> Result<Vec<models::BudgetStatus>, sqlx::Error> {
+    let records = sqlx::query!(
+        r#"SELECT b.id, b.group_id, b.amount, b.currency_id, b.period AS "period!: models::BudgetPeriod", b.category, b.threshold, b.created_by_id, b.created_at,
+                  p.period_start AS "period_start!",
+                  COALESCE(SUM(e.amount), 0) AS "consumed!"
+           FROM budgets b
+           CROSS JOIN LATERAL (
+             SELECT date_trunc(CASE b.period WHEN 'weekly' THEN 'week' WHEN 'monthly' THEN 'month' ELSE 'year' END, $2::timestamptz) AS period_start,
+                    CASE b.period WHEN 'weekly' THEN interval '1 week' WHEN 'monthly' THEN interval '1 month' ELSE interval '1 year' END AS period_length
+           ) p
+           LEFT JOIN expenses e
+             ON e.group_id = b.group_id
+             AND e.currency_id = b.currency_id
+             AND (b.category IS NULL OR e.category = b.category)
+             AND e.deleted = false
+             AND e.draft = false
+             AND e.split_strategy ->> 'kind' != 'payment'
+             AND e.date >= p.period_start AND e.date < p.period_start + p.period_length
+           WHERE b.group_id = $1
+           AND b.deleted = false
+           GROUP BY b.id, p.period_start
+           ORDER BY b.id"#,
+        group_id,
+        at,
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(records
+        .into_iter()
+        .map(|r| models::BudgetStatus {
+            budget: models::Budget {
+                id: Some(r.id),
+                group_id: Some(r.group_id),
+                amount: r.amount,
+                currency_id: r.currency_id,
+                period: r.period,
+                category: r.category,
+                threshold: r.threshold,
+                created_by_id: Some(r.created_by_id),
+                created_at: Some(r.created_at),
+            },
+            period_start: r.period_start,
+            consumed: r.consumed,
+        })
+        .collect())
+}
+
+/// Notifies the members of the group for every budget that the given expenses push past its
+/// threshold, returns the notifications created. The budgets of the group stay locked until the
+/// end of the transaction, so that concurrent expenses see each other and each crossing gets
+/// notified once.
+async fn insert_budget_alerts(
+    group_id: GroupId,
+    expenses: &[Expense],
+    conn: &mut PgConnection,
+) -> Result<Vec<models::CreatedNotification>, sqlx::Error> {
+    let counted = expenses
+        .iter()
+        .filter(|e| !e.draft && !matches!(e.split_strategy, SplitStrategy::Payment { .. }))
+        .collect::<Vec<_>>();
+
+    if counted.is_empty() {
+        return Ok(Vec::default());
+    }
+
+    sqlx::query!(
+        r#"SELECT id
+           FROM budgets
+           WHERE group_id = $1
+           AND deleted = false
+           ORDER BY id
+           FOR UPDATE"#,
+        group_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    // what the expenses add to each budget, in the period of each of them
+    let mut added = HashMap::<(models::BudgetId, chrono::DateTime<chrono::Utc>), _>::default();
+    for expense in counted {
+        let statuses = find_budget_statuses(group_id, expense.date, &mut *conn).await?;
+
+        for status in statuses {
+            let budget = &status.budget;
+            if budget.currency_id != expense.currency_id
+                || (budget.category.is_some() && budget.category != expense.category)
+            {
+                continue;
+            }
+
+            let key = (budget.id.expect("budget id"), status.period_start);
+            added.entry(key).or_insert((status, 0f64)).1 += expense.amount;
+        }
+    }
+
+    let crossed = added.into_values().filter(|(s, amount)| {
+        let limit = s.budget.amount * s.budget.threshold;
+
+        s.consumed - amount < limit && s.consumed >= limit
+    });
+
+    let mut notifications = Vec::default();
+    for (status, _) in crossed {
+        let alert = models::NotificationKind::BudgetAlert {
+            group_id,
+            budget_id: status.budget.id.expect("budget id"),
+            consumed: status.consumed,
+        };
+        let alert = serde_json::to_value(alert).expect("serialized value");
+
+        let alerts = sqlx::query_as!(
+            models::CreatedNotification,
+            r#"WITH inserted AS (
+                 INSERT INTO notifications (user_id, data)
+                 SELECT m.user_id, $2
+                 FROM memberships m
+                 WHERE m.group_id = $1
+                 AND m.status = 'joined'
+                 RETURNING id, user_id
+               )
+               SELECT n.id AS "id!", u.email AS "email!"
+               FROM inserted n, users u
+               WHERE u.id = n.user_id
+             "#,
+            group_id,
+            alert,
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        notifications.extend(alerts);
+    }
+
+    Ok(notifications)
+}
+
 pub(crate) async fn validate_refresh_token(
     refresh_token: &str,
     user_id: &str,
@@ -605,6 +2054,31 @@ LIMIT 1
     Ok(result.is_some())
 }
 
+/// Whether the device of the user holds a refresh token still valid.
+pub async fn is_active_device(
+    email: &str,
+    device_id: &str,
+    pool: &DbPool,
+) -> Result<bool, sqlx::Error> {
+    let record = sqlx::query!(
+        r#"SELECT EXISTS (
+             SELECT 1
+             FROM refresh_tokens r, users u
+             WHERE u.email = $1
+             AND r.user_id = u.id
+             AND r.device_id = $2
+             AND NOW() < r.expires_at
+             AND r.is_revoked = false
+           ) AS "active!""#,
+        email,
+        device_id,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(record.active)
+}
+
 pub(crate) async fn persist_refresh_token(
     user: &models::User,
     refresh_token: &str,
@@ -657,7 +2131,7 @@ pub enum NotificationDtoKind {
         created_by: models::User,
     },
     Payment {
-        group: models::Group,
+        group: Option<models::Group>,
         currency_id: models::CurrencyId,
         amount: f64,
         date: chrono::DateTime<chrono::Utc>,
@@ -665,6 +2139,20 @@ pub enum NotificationDtoKind {
         recipient: models::User,
         created_by: models::User,
     },
+    BudgetAlert {
+        group: models::Group,
+        budget: models::Budget,
+        consumed: f64,
+    },
+    DirectExpense {
+        expense_id: models::ExpenseId,
+        description: String,
+        currency_id: models::CurrencyId,
+        amount: f64,
+        date: chrono::DateTime<chrono::Utc>,
+        split_strategy: SplitStrategy,
+        created_by: models::User,
+    },
 }
 
 #[derive(Serialize, Deserialize)]
@@ -679,3 +2167,58 @@ pub struct NotificationDto {
 
     pub created_at: chrono::DateTime<chrono::Utc>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMAIL: &str = "mutations@example.com";
+
+    /// Transaction on the database in `DATABASE_URL`, with the migrations applied, that is
+    /// never committed. `None` when there is no database to run against.
+    async fn scratch() -> Option<sqlx::Transaction<'static, Postgres>> {
+        let connspec = std::env::var("DATABASE_URL").ok()?;
+        let pool = create_connection_pool(&connspec).await.expect("database");
+        let mut tx = pool.begin().await.expect("transaction");
+
+        sqlx::query("INSERT INTO users (id, email, status) VALUES ('mutations', $1, 'active')")
+            .bind(EMAIL)
+            .execute(&mut *tx)
+            .await
+            .expect("user");
+
+        Some(tx)
+    }
+
+    #[actix_web::test]
+    async fn failed_mutations_are_claimed_again() {
+        let Some(mut tx) = scratch().await else {
+            return;
+        };
+        let mutation_id = Uuid::new_v4();
+
+        // applying fails, the attempt rolls back along with its claim
+        let mut attempt = tx.begin().await.unwrap();
+        let claimed = claim_mutation(EMAIL, mutation_id, &mut attempt).await;
+        assert!(claimed.unwrap().is_none());
+        drop(attempt);
+
+        let mut attempt = tx.begin().await.unwrap();
+        let claimed = claim_mutation(EMAIL, mutation_id, &mut attempt).await;
+        assert!(claimed.unwrap().is_none());
+
+        let outcome = models::MutationOutcome::Rejected {
+            error: "unknown expense".to_owned(),
+        };
+        complete_mutation(EMAIL, mutation_id, &outcome, &mut *attempt)
+            .await
+            .unwrap();
+        attempt.commit().await.unwrap();
+
+        let claimed = claim_mutation(EMAIL, mutation_id, &mut tx).await;
+        assert!(matches!(
+            claimed.unwrap(),
+            Some(models::MutationOutcome::Rejected { error }) if error == "unknown expense"
+        ));
+    }
+}
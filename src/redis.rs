@@ -1,21 +1,313 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
 use bb8_redis::{
-    bb8::{self, Pool},
+    bb8::{self, Pool, PooledConnection, RunError},
     RedisConnectionManager,
 };
-use redis::{AsyncCommands, RedisError};
+use futures::StreamExt;
+use redis::aio::{MultiplexedConnection, PubSubSink};
+use redis::streams::{StreamRangeReply, StreamReadOptions, StreamReadReply};
+use redis::{AsyncCommands, Client, RedisError};
+
+use crate::bus::{
+    BusError, BusMessage, EventBus, QueueReader, Subscriber, Subscription,
+    EVENTS_RETENTION_SECONDS, PRESENCE_TIMEOUT_SECONDS,
+};
+
+type RedisPool = Pool<RedisConnectionManager>;
 
-pub type RedisPool = Pool<RedisConnectionManager>;
+/// Sorted set of the users online, scored by the last time they synced.
+const PRESENCE_KEY: &str = "presence";
 
-pub async fn create_redis_pool(connspec: &str) -> Result<RedisPool, RedisError> {
+const SWEEP_PRESENCE: &str = r#"
+local users = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1])
+if #users > 0 then
+    redis.call('ZREM', KEYS[1], unpack(users))
+end
+return users
+"#;
+
+const ACQUIRE_LEASE: &str = r#"
+if redis.call('SET', KEYS[1], ARGV[1], 'NX', 'PX', ARGV[2]) then
+    return 1
+end
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    redis.call('PEXPIRE', KEYS[1], ARGV[2])
+    return 1
+end
+return 0
+"#;
+
+/// Connections for the short commands.
+const POOL_SIZE: u32 = 15;
+/// Connections for the blocking reads of long polls, each one taken for up to a heartbeat.
+const READ_POOL_SIZE: u32 = 32;
+
+async fn create_redis_pool(connspec: &str, size: u32) -> Result<RedisPool, RedisError> {
     let manager = bb8_redis::RedisConnectionManager::new(connspec).expect("connectaction mgr");
-    bb8::Pool::builder().max_size(15).build(manager).await
+    bb8::Pool::builder().max_size(size).build(manager).await
+}
+
+/// Event bus backed by redis, shared by all the replicas.
+///
+/// Every replica opens up to `POOL_SIZE` connections for short commands, up to `READ_POOL_SIZE`
+/// for the long polls of `/sync` (which wait for a free one when all are taken), one for every
+/// open `/events/stream` and `/ws`, and one per subscription of the workers.
+pub struct RedisBus {
+    pool: RedisPool,
+    read_pool: RedisPool,
+    client: Client,
+}
+
+struct RedisSubscriber(PubSubSink);
+
+/// Blocks its connection while waiting for entries.
+enum RedisReader {
+    /// opened for a stream and closed along with it
+    Dedicated(MultiplexedConnection),
+    /// taken from the read pool for a single long poll
+    Pooled(PooledConnection<'static, RedisConnectionManager>),
+}
+
+impl RedisBus {
+    pub async fn connect(connspec: &str) -> Result<Self, RedisError> {
+        let client = Client::open(connspec)?;
+        let pool = create_redis_pool(connspec, POOL_SIZE).await?;
+        let read_pool = create_redis_pool(connspec, READ_POOL_SIZE).await?;
+
+        Ok(Self {
+            pool,
+            read_pool,
+            client,
+        })
+    }
+
+    async fn pooled(&self) -> Result<PooledConnection<'_, RedisConnectionManager>, RedisError> {
+        self.pool.get().await.map_err(pool_error)
+    }
+}
+
+fn pool_error(e: RunError<RedisError>) -> RedisError {
+    match e {
+        RunError::User(e) => e,
+        RunError::TimedOut => RedisError::from((redis::ErrorKind::IoError, "redis pool timed out")),
+    }
+}
+
+impl RedisReader {
+    fn connection(&mut self) -> &mut MultiplexedConnection {
+        match self {
+            RedisReader::Dedicated(connection) => connection,
+            RedisReader::Pooled(connection) => connection,
+        }
+    }
+}
+
+/// Stream with the events of the user, every device reads it from its own cursor.
+fn events_log(email: &str) -> String {
+    format!("log.{}", email)
+}
+
+/// Hash with the cursors of the devices of the user.
+fn device_cursors(email: &str) -> String {
+    format!("cursors.{}", email)
+}
+
+#[async_trait]
+impl EventBus for RedisBus {
+    async fn publish(&self, topic: &str, payload: &str) -> Result<(), BusError> {
+        let mut redis = self.pooled().await?;
+
+        redis.publish::<&str, &str, ()>(topic, payload).await?;
+
+        Ok(())
+    }
+
+    async fn subscribe(&self) -> Result<Subscription, BusError> {
+        let (sink, stream) = self.client.get_async_pubsub().await?.split();
+
+        // all the subscriptions are to patterns
+        let messages = stream.filter_map(|msg| async move {
+            Some(BusMessage {
+                topic: msg.get_channel_name().to_owned(),
+                pattern: msg.get_pattern().ok()?,
+                payload: msg.get_payload().ok()?,
+            })
+        });
+
+        Ok(Subscription {
+            subscriber: Box::new(RedisSubscriber(sink)),
+            messages: messages.boxed(),
+        })
+    }
+
+    async fn push(&self, email: &str, payload: &str) -> Result<(), BusError> {
+        let mut redis = self.pooled().await?;
+        let log = events_log(email);
+        let min_id = chrono::Utc::now().timestamp_millis() - EVENTS_RETENTION_SECONDS * 1000;
+
+        redis::pipe()
+            .cmd("XADD")
+            .arg(&log)
+            .arg("MINID")
+            .arg("~")
+            .arg(min_id)
+            .arg("*")
+            .arg("event")
+            .arg(payload)
+            .ignore()
+            .expire(&log, EVENTS_RETENTION_SECONDS)
+            .ignore()
+            .query_async::<()>(&mut *redis)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn reader(&self) -> Result<Box<dyn QueueReader>, BusError> {
+        let connection = self.client.get_multiplexed_async_connection().await?;
+
+        Ok(Box::new(RedisReader::Dedicated(connection)))
+    }
+
+    async fn poll_reader(&self) -> Result<Box<dyn QueueReader>, BusError> {
+        let connection = self.read_pool.get_owned().await.map_err(pool_error)?;
+
+        Ok(Box::new(RedisReader::Pooled(connection)))
+    }
+
+    async fn last_cursor(&self, email: &str) -> Result<Option<String>, BusError> {
+        let mut redis = self.pooled().await?;
+
+        let last = redis
+            .xrevrange_count::<String, &str, &str, usize, StreamRangeReply>(
+                events_log(email),
+                "+",
+                "-",
+                1,
+            )
+            .await?;
+
+        Ok(last.ids.first().map(|e| e.id.clone()))
+    }
+
+    async fn save_cursor(&self, email: &str, device: &str, cursor: &str) -> Result<(), BusError> {
+        let mut redis = self.pooled().await?;
+        let cursors = device_cursors(email);
+
+        redis::pipe()
+            .hset(&cursors, device, cursor)
+            .ignore()
+            .expire(&cursors, EVENTS_RETENTION_SECONDS)
+            .ignore()
+            .query_async::<()>(&mut *redis)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn device_cursor(&self, email: &str, device: &str) -> Result<Option<String>, BusError> {
+        let mut redis = self.pooled().await?;
+
+        Ok(redis.hget(device_cursors(email), device).await?)
+    }
+
+    async fn touch_presence(&self, email: &str) -> Result<bool, BusError> {
+        let mut redis = self.pooled().await?;
+        let now = chrono::Utc::now().timestamp();
+
+        let (added,) = redis::pipe()
+            .zadd(PRESENCE_KEY, email, now)
+            .expire(PRESENCE_KEY, PRESENCE_TIMEOUT_SECONDS * 10)
+            .ignore()
+            .query_async::<(i64,)>(&mut *redis)
+            .await?;
+
+        Ok(added > 0)
+    }
+
+    async fn sweep_presence(&self) -> Result<Vec<String>, BusError> {
+        let mut redis = self.pooled().await?;
+        let cutoff = chrono::Utc::now().timestamp() - PRESENCE_TIMEOUT_SECONDS;
+
+        // exclusive, the users seen right at the cutoff are still online
+        Ok(redis::Script::new(SWEEP_PRESENCE)
+            .key(PRESENCE_KEY)
+            .arg(format!("({}", cutoff))
+            .invoke_async(&mut *redis)
+            .await?)
+    }
+
+    async fn online_users(&self) -> Result<Vec<String>, BusError> {
+        let mut redis = self.pooled().await?;
+        let cutoff = chrono::Utc::now().timestamp() - PRESENCE_TIMEOUT_SECONDS;
+
+        Ok(redis::cmd("ZRANGEBYSCORE")
+            .arg(PRESENCE_KEY)
+            .arg(cutoff)
+            .arg("+inf")
+            .query_async(&mut *redis)
+            .await?)
+    }
+
+    async fn acquire_lease(
+        &self,
+        lease: &str,
+        holder: &str,
+        millis: u64,
+    ) -> Result<bool, BusError> {
+        let mut redis = self.pooled().await?;
+
+        Ok(redis::Script::new(ACQUIRE_LEASE)
+            .key(lease)
+            .arg(holder)
+            .arg(millis)
+            .invoke_async(&mut *redis)
+            .await?)
+    }
+}
+
+#[async_trait]
+impl QueueReader for RedisReader {
+    async fn read_after(
+        &mut self,
+        email: &str,
+        cursor: &str,
+        count: usize,
+        timeout: Duration,
+    ) -> Result<Vec<(String, String)>, BusError> {
+        let options = StreamReadOptions::default()
+            .block(timeout.as_millis() as usize)
+            .count(count);
+        let reply = self
+            .connection()
+            .xread_options::<String, &str, Option<StreamReadReply>>(
+                &[events_log(email)],
+                &[cursor],
+                &options,
+            )
+            .await?;
+
+        Ok(reply
+            .into_iter()
+            .flat_map(|r| r.keys)
+            .flat_map(|k| k.ids)
+            .filter_map(|e| {
+                let payload = e.get::<String>("event")?;
+                Some((e.id, payload))
+            })
+            .collect())
+    }
 }
 
-pub async fn publish_topic(redis: RedisPool, topic: String, payload: String) {
-    let mut redis = redis.get().await.expect("pooled conn");
+#[async_trait]
+impl Subscriber for RedisSubscriber {
+    async fn subscribe(&mut self, pattern: &str) -> Result<(), BusError> {
+        Ok(self.0.psubscribe(pattern).await?)
+    }
 
-    redis
-        .publish::<String, String, ()>(topic, payload)
-        .await
-        .expect("published topic");
+    async fn unsubscribe(&mut self, pattern: &str) -> Result<(), BusError> {
+        Ok(self.0.punsubscribe(pattern).await?)
+    }
 }
@@ -6,16 +6,16 @@ use uuid::Uuid;
 
 use ::auth::auth::{AuthData, LoginData};
 
+use crate::bus::{publish_topic, Bus};
 use crate::models::{User, UserStatus};
 use crate::queries::{self, DbPool};
-use crate::redis::{publish_topic, RedisPool};
 
 #[get("/auth")]
 async fn auth(
     req: HttpRequest,
     Query(auth_data): Query<AuthData>,
     pool: web::Data<DbPool>,
-    redis: web::Data<RedisPool>,
+    bus: web::Data<Bus>,
 ) -> Result<HttpResponse> {
     let pool_ = pool.clone();
     let handle_user = |payload: IdPayload| async move {
@@ -41,8 +41,8 @@ async fn auth(
             })
             .expect("user insert");
 
-        let redis = redis.get_ref();
-        spawn(publish_topic(redis.clone(), "auth.login".to_owned(), email));
+        let bus = bus.get_ref();
+        spawn(publish_topic(bus.clone(), "auth.login".to_owned(), email));
 
         user
     };
@@ -0,0 +1,403 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::http::header::{CacheControl, CacheDirective};
+use actix_web::rt::spawn;
+use actix_web::web::Bytes;
+use actix_web::{
+    error::{ErrorBadRequest, ErrorForbidden, ErrorInternalServerError},
+    get, web, Error, HttpRequest, HttpResponse,
+};
+use actix_ws::{Message, Session};
+use futures::stream;
+use serde::{Deserialize, Serialize};
+
+use ::auth::identity::Identity;
+
+use crate::bus::{
+    publish_event, publish_topic, Bus, BusError, QueueReader, EVENTS_RETENTION_SECONDS,
+};
+use crate::models::{self, Event, EventPayload};
+use crate::queries::{can_type, is_active_device, DbPool};
+
+const LAST_EVENT_ID: &str = "Last-Event-ID";
+
+/// Time waiting for events before sending a heartbeat.
+const HEARTBEAT: Duration = Duration::from_secs(15);
+/// Time between the presence announcements of open streams, well within the presence timeout.
+const PRESENCE_INTERVAL: Duration = Duration::from_secs(30);
+/// Max events read from the log at once.
+const MAX_EVENTS: usize = 100;
+
+/// Cursor of an empty log.
+const START: &str = "0-0";
+
+/// Events after the cursor sent by the client, along with the cursor to send next time.
+#[derive(Serialize)]
+pub struct SyncResponse {
+    pub cursor: String,
+    pub events: Vec<Event>,
+}
+
+/// Messages sent by the clients through the websocket.
+#[derive(Deserialize)]
+#[serde(rename_all(serialize = "snake_case", deserialize = "snake_case"))]
+#[serde(tag = "kind")]
+enum ClientMessage {
+    Presence,
+    Typing {
+        group_id: models::GroupId,
+        expense_id: Option<models::ExpenseId>,
+    },
+}
+
+struct EventStream {
+    bus: Bus,
+    reader: Box<dyn QueueReader>,
+    email: String,
+    cursor: String,
+    /// device whose cursor is saved as the stream goes
+    device: Option<String>,
+    /// last time the user was announced as present, `None` before the first read
+    presence_at: Option<Instant>,
+    /// chunks ready to be sent before waiting for new events
+    pending: VecDeque<Bytes>,
+}
+
+/// Server-Sent Events stream of the events of the user, resumable through the `Last-Event-ID`
+/// header (or the `since` parameter, or the cursor of the device) while they remain in the log.
+#[get("/events/stream")]
+pub async fn event_stream(
+    identity: Identity,
+    req: HttpRequest,
+    query: web::Query<models::SyncQuery>,
+    bus: web::Data<Bus>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, Error> {
+    let email = identity.claims().email;
+    let web::Query(models::SyncQuery { since, device_id }) = query;
+
+    let since = req
+        .headers()
+        .get(LAST_EVENT_ID)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned)
+        .or(since);
+
+    let device = resolve_device(&email, device_id, &pool).await?;
+    let (cursor, reset) = resolve_cursor(&bus, &email, since, device.as_deref()).await?;
+    let reader = bus.reader().await.map_err(ErrorInternalServerError)?;
+
+    let mut pending = VecDeque::default();
+    if reset {
+        pending.push_back(chunk(&cursor, &Event::Reset));
+    }
+
+    let state = EventStream {
+        bus: bus.as_ref().clone(),
+        reader,
+        email,
+        cursor,
+        device,
+        presence_at: None,
+        pending,
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .streaming(stream::unfold(state, next_chunk)))
+}
+
+async fn next_chunk(mut state: EventStream) -> Option<(Result<Bytes, Error>, EventStream)> {
+    if let Some(chunk) = state.pending.pop_front() {
+        return Some((Ok(chunk), state));
+    }
+
+    // keeps the activity detector informed that the user is still around
+    if state
+        .presence_at
+        .is_none_or(|at| at.elapsed() >= PRESENCE_INTERVAL)
+    {
+        if let Err(e) = state.bus.publish("sync", &state.email).await {
+            eprintln!("bus error:\n{}", e);
+            return None;
+        }
+        state.presence_at = Some(Instant::now());
+    }
+
+    // the chunks read before are sent by now
+    if let Some(device) = &state.device {
+        save_device_cursor(&state.bus, &state.email, device, &state.cursor).await;
+    }
+
+    let read = read_events(state.reader.as_mut(), &state.email, &state.cursor).await;
+    let (cursor, events) = match read {
+        Ok(read) => read,
+        Err(e) => {
+            // the stream ends, clients reconnect from their last event
+            eprintln!("bus error:\n{}", e);
+            return None;
+        }
+    };
+
+    state.cursor = cursor;
+    state
+        .pending
+        .extend(events.iter().map(|(id, event)| chunk(id, event)));
+
+    let chunk = state
+        .pending
+        .pop_front()
+        .unwrap_or_else(|| Bytes::from_static(b": heartbeat\n\n"));
+
+    Some((Ok(chunk), state))
+}
+
+/// Device of the user the client reads for, which must hold a valid refresh token.
+pub async fn resolve_device(
+    email: &str,
+    device_id: Option<String>,
+    pool: &DbPool,
+) -> Result<Option<String>, Error> {
+    let Some(device_id) = device_id else {
+        return Ok(None);
+    };
+
+    let active = is_active_device(email, &device_id, pool)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    if !active {
+        return Err(ErrorForbidden("unknown device"));
+    }
+
+    Ok(Some(device_id))
+}
+
+/// Where to read the log of the user from, the cursor of the device standing in for a missing
+/// one. Clients without a cursor, or with one past the retention, start from the current end of
+/// the log, the latter being flagged to reset.
+pub async fn resolve_cursor(
+    bus: &Bus,
+    email: &str,
+    since: Option<String>,
+    device: Option<&str>,
+) -> Result<(String, bool), Error> {
+    let since = match (since, device) {
+        (Some(since), _) => Some(since),
+        (None, Some(device)) => bus
+            .device_cursor(email, device)
+            .await
+            .map_err(ErrorInternalServerError)?,
+        (None, None) => None,
+    };
+
+    if let Some(since) = &since {
+        let millis = cursor_millis(since).ok_or_else(|| ErrorBadRequest("invalid cursor"))?;
+
+        let retention = chrono::Utc::now().timestamp_millis() - EVENTS_RETENTION_SECONDS * 1000;
+        if millis == 0 || millis as i64 >= retention {
+            return Ok((since.clone(), false));
+        }
+    }
+
+    let cursor = bus
+        .last_cursor(email)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .unwrap_or_else(|| START.to_owned());
+
+    Ok((cursor, since.is_some()))
+}
+
+/// Waits up to a heartbeat for events of the user after the cursor, returns them along with the
+/// new cursor.
+pub async fn read_events(
+    reader: &mut dyn QueueReader,
+    email: &str,
+    cursor: &str,
+) -> Result<(String, Vec<(String, Event)>), BusError> {
+    let entries = reader
+        .read_after(email, cursor, MAX_EVENTS, HEARTBEAT)
+        .await?;
+
+    let cursor = entries
+        .last()
+        .map(|(id, _)| id.clone())
+        .unwrap_or_else(|| cursor.to_owned());
+
+    let events = entries
+        .into_iter()
+        .filter_map(|(id, payload)| {
+            let payload = serde_json::from_str::<EventPayload>(&payload).ok()?;
+            Some((id, payload.event))
+        })
+        .collect();
+
+    Ok((cursor, events))
+}
+
+/// Bidirectional channel delivering the same events as `/sync` and taking presence and typing
+/// messages from the clients.
+#[get("/ws")]
+pub async fn websocket(
+    identity: Identity,
+    req: HttpRequest,
+    body: web::Payload,
+    query: web::Query<models::SyncQuery>,
+    bus: web::Data<Bus>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, Error> {
+    let email = identity.claims().email;
+
+    let web::Query(models::SyncQuery { since, device_id }) = query;
+
+    let device = resolve_device(&email, device_id, &pool).await?;
+    let cursor = resolve_cursor(&bus, &email, since, device.as_deref()).await?;
+    let reader = bus.reader().await.map_err(ErrorInternalServerError)?;
+
+    let (response, session, mut messages) = actix_ws::handle(&req, body)?;
+
+    let closed = Arc::new(AtomicBool::new(false));
+    let bus = bus.as_ref().clone();
+    let pool = pool.get_ref().clone();
+
+    spawn(deliver_events(
+        session.clone(),
+        bus.clone(),
+        reader,
+        (email.clone(), device),
+        cursor,
+        closed.clone(),
+    ));
+
+    spawn(async move {
+        let mut session = session;
+
+        while let Some(Ok(message)) = messages.recv().await {
+            match message {
+                Message::Text(text) => handle_client_message(&bus, &pool, &email, &text),
+                Message::Ping(bytes) => {
+                    let pong = session.pong(&bytes).await;
+                    if pong.is_err() {
+                        break;
+                    }
+                }
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+
+        closed.store(true, Ordering::Relaxed);
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
+}
+
+fn handle_client_message(bus: &Bus, pool: &DbPool, email: &str, text: &str) {
+    match serde_json::from_str::<ClientMessage>(text) {
+        Ok(ClientMessage::Presence) => {
+            spawn(publish_topic(
+                bus.clone(),
+                "sync".to_owned(),
+                email.to_owned(),
+            ));
+        }
+        Ok(ClientMessage::Typing {
+            group_id,
+            expense_id,
+        }) => {
+            let topic = match expense_id {
+                Some(expense_id) => format!("groups.{}.typing.{}", group_id, expense_id),
+                None => format!("groups.{}.typing", group_id),
+            };
+            let event = Event::Typing {
+                group_id,
+                expense_id,
+                email: email.to_owned(),
+            };
+
+            let bus = bus.clone();
+            let pool = pool.clone();
+            let email = email.to_owned();
+            spawn(async move {
+                // only the members of the group see who is typing in it
+                match can_type(&email, group_id, expense_id, &pool).await {
+                    Ok(true) => publish_event(bus, topic, email, event).await,
+                    Ok(false) => {}
+                    Err(e) => eprintln!("db error:\n{}", e),
+                }
+            });
+        }
+        // invalid messages are dropped
+        Err(_) => {}
+    }
+}
+
+/// Sends the events in the same shape as `/sync` responses. The presence of the user is left to
+/// the presence messages of the client.
+async fn deliver_events(
+    mut session: Session,
+    bus: Bus,
+    mut reader: Box<dyn QueueReader>,
+    (email, device): (String, Option<String>),
+    (mut cursor, reset): (String, bool),
+    closed: Arc<AtomicBool>,
+) {
+    let mut pending = if reset { vec![Event::Reset] } else { vec![] };
+
+    while !closed.load(Ordering::Relaxed) {
+        if !pending.is_empty() {
+            let response = SyncResponse {
+                cursor: cursor.clone(),
+                events: std::mem::take(&mut pending),
+            };
+            let response = serde_json::to_string(&response).expect("serialized events");
+            if session.text(response).await.is_err() {
+                break;
+            }
+
+            if let Some(device) = &device {
+                save_device_cursor(&bus, &email, device, &cursor).await;
+            }
+        }
+
+        match read_events(reader.as_mut(), &email, &cursor).await {
+            Ok((next, events)) => {
+                cursor = next;
+                pending.extend(events.into_iter().map(|(_, event)| event));
+
+                if pending.is_empty() && session.ping(b"").await.is_err() {
+                    break;
+                }
+            }
+            Err(e) => {
+                eprintln!("bus error:\n{}", e);
+                let _ = session.close(None).await;
+                break;
+            }
+        }
+    }
+}
+
+/// Saves how far the device got, failures only cost the device a reset later on.
+pub async fn save_device_cursor(bus: &Bus, email: &str, device: &str, cursor: &str) {
+    if let Err(e) = bus.save_cursor(email, device, cursor).await {
+        eprintln!("bus error:\n{}", e);
+    }
+}
+
+/// Milliseconds part of a stream id, `None` when the cursor is not one.
+fn cursor_millis(cursor: &str) -> Option<u64> {
+    let (millis, sequence) = cursor.split_once('-').unwrap_or((cursor, "0"));
+    sequence.parse::<u64>().ok()?;
+    millis.parse::<u64>().ok()
+}
+
+fn chunk(id: &str, event: &Event) -> Bytes {
+    let data = serde_json::to_string(event).expect("serialized event");
+    Bytes::from(format!("id: {}\ndata: {}\n\n", id, data))
+}
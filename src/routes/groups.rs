@@ -1,29 +1,43 @@
 use std::collections::{HashMap, HashSet};
-use std::num::NonZeroUsize;
 
 use actix_web::delete;
+use actix_web::http::header::{ContentDisposition, ETag, EntityTag, IfMatch};
+use actix_web::middleware::from_fn;
 use actix_web::rt::spawn;
+use actix_web::web::Bytes;
 use actix_web::{
-    error::ErrorInternalServerError, get, post, put, web, Error, HttpResponse, Result,
+    error::{
+        ErrorBadRequest, ErrorInternalServerError, ErrorNotFound, ErrorPreconditionFailed,
+        ErrorPreconditionRequired,
+    },
+    get, post, put, web, Error, HttpResponse, Result,
 };
-use redis::AsyncCommands;
-use serde::{Deserialize, Serialize};
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+use sqlx::PgConnection;
 
 use ::auth::identity::Identity;
 
-use crate::models::{self, Balance, SplitStrategy};
+use crate::balances::{
+    balances_from_pairs, compute_balance_history, compute_balances, compute_friend_balances,
+};
+use crate::bus::{publish_event, Bus, BusError};
+use crate::export::{ExportContext, ExportFormat, ExportHeader, ExportQuery};
+use crate::idempotency::idempotency;
+use crate::import::{parse_splitwise, SplitwiseImport};
+use crate::models::{self, Event, SplitStrategy};
 use crate::queries::DbPool;
-use crate::redis::{publish_topic, RedisPool};
-
-const _15_SECONDS: f64 = 15f64;
+use crate::routes::events::{
+    read_events, resolve_cursor, resolve_device, save_device_cursor, SyncResponse,
+};
+use crate::statements::{
+    is_duplicate, parse_statement, ConfirmDrafts, StatementImport, StatementReport,
+};
+use crate::stats::compute_group_stats;
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Hash)]
-#[serde(rename_all(serialize = "snake_case", deserialize = "snake_case"))]
-#[serde(tag = "kind")]
-enum Event {
-    Group { id: models::GroupId, field: String },
-    Notification { id: i32 },
-}
+const MAX_BATCH_SIZE: usize = 500;
+/// Chunks of an export encoded ahead of the client.
+const EXPORT_BUFFER: usize = 16;
 
 #[get("/currencies")]
 pub async fn fetch_currencies(pool: web::Data<DbPool>) -> Result<HttpResponse, Error> {
@@ -35,55 +49,63 @@ pub async fn fetch_currencies(pool: web::Data<DbPool>) -> Result<HttpResponse, E
 }
 
 #[get("/sync")]
-pub async fn sync(identity: Identity, redis: web::Data<RedisPool>) -> Result<HttpResponse, Error> {
+pub async fn sync(
+    identity: Identity,
+    query: web::Query<models::SyncQuery>,
+    bus: web::Data<Bus>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, Error> {
     let email = identity.claims().email;
+    let web::Query(models::SyncQuery { since, device_id }) = query;
 
-    let mut redis = redis.get().await.expect("pooled conn");
-    redis
-        .publish::<&str, &str, ()>("sync", &email)
-        .await
-        .expect("publish sync");
-
-    let event = redis
-        .brpop::<String, Option<(String, String)>>(format!("events.{}", email), _15_SECONDS)
-        .await
-        .expect("brpop events");
+    let device = resolve_device(&email, device_id, &pool).await?;
+    let (cursor, reset) = resolve_cursor(&bus, &email, since, device.as_deref()).await?;
 
-    if event.is_none() {
-        return Ok(HttpResponse::Ok().json(Vec::<Event>::default()));
+    // asking from a cursor acknowledges the events before it
+    if let Some(device) = &device {
+        save_device_cursor(&bus, &email, device, &cursor).await;
+    }
+    if reset {
+        return Ok(HttpResponse::Ok().json(SyncResponse {
+            cursor,
+            events: vec![Event::Reset],
+        }));
     }
 
-    let mut events = vec![event.unwrap().1];
+    // keeps the activity detector informed that the user is still around
+    bus.publish("sync", &email)
+        .await
+        .map_err(handle_unknown_bus_error)?;
 
-    let more = redis
-        .rpop::<String, Vec<String>>(format!("events.{}", email), NonZeroUsize::new(99usize))
+    let mut reader = bus.poll_reader().await.map_err(handle_unknown_bus_error)?;
+    let (cursor, events) = read_events(reader.as_mut(), &email, &cursor)
         .await
-        .expect("rpop events");
-    events.extend(more);
+        .map_err(handle_unknown_bus_error)?;
 
-    let mut set = HashSet::new();
-    for event in events {
-        if event.starts_with("groups.") {
-            let segments = event.split('.');
-            let mut segments = segments.skip(1);
+    // clients apply the events in order
+    let events = events.into_iter().map(|(_, event)| event).collect();
 
-            let new = Event::Group {
-                id: segments
-                    .next()
-                    .expect("group id")
-                    .parse()
-                    .expect("deserialize"),
-                field: segments.next().expect("field").to_owned(),
-            };
-            set.insert(new);
-        } else if event.starts_with("users.") {
-            let new = Event::Notification { id: 0 }; // TODO - nevermind for now - moliva - 2024/04/10
-            set.insert(new);
-        }
-        // TODO - we might be getting some other stuff from the rpop (the channel name) - moliva - 2024/04/10
+    Ok(HttpResponse::Ok().json(SyncResponse { cursor, events }))
+}
+
+/// Everything that changed for the user after the cursor, independent of the events.
+#[get("/changes")]
+pub async fn fetch_changes(
+    identity: Identity,
+    query: web::Query<models::ChangesQuery>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, Error> {
+    let email = identity.claims().email;
+
+    if query.since < 0 {
+        return Err(ErrorBadRequest("invalid cursor"));
     }
 
-    Ok(HttpResponse::Ok().json(&set))
+    let changes = crate::queries::find_changes(&email, query.since, &pool)
+        .await
+        .map_err(handle_unknown_error)?;
+
+    Ok(HttpResponse::Ok().json(&changes))
 }
 
 #[get("/notifications")]
@@ -114,61 +136,85 @@ pub async fn fetch_groups(
     Ok(HttpResponse::Ok().json(&groups))
 }
 
-#[post("/groups")]
+#[post("/groups", wrap = "from_fn(idempotency)")]
 pub async fn create_group(
     identity: Identity,
     group: web::Json<models::Group>,
     pool: web::Data<DbPool>,
-    redis: web::Data<RedisPool>,
+    bus: web::Data<Bus>,
 ) -> Result<HttpResponse, Error> {
     let email = identity.claims().email;
-    let web::Json(group) = group;
+    let web::Json(mut group) = group;
 
-    let group_id = crate::queries::create_group(&email, group, &pool)
+    let group_id = crate::queries::create_group(&email, group.clone(), &pool)
         .await
         .map_err(handle_unknown_error)?;
 
-    let redis = redis.as_ref().clone();
+    let user = crate::queries::find_user(&email, pool.get_ref())
+        .await
+        .map_err(handle_unknown_error)?;
 
-    spawn(publish_topic(
-        redis.clone(),
+    group.id = Some(group_id);
+    group.creator_id = Some(user.id.clone());
+
+    let bus = bus.as_ref().clone();
+
+    spawn(publish_event(
+        bus.clone(),
         format!("groups.{}.config", group_id),
         email.clone(),
+        Event::GroupConfigChanged { group },
     ));
 
-    spawn(publish_topic(
-        redis,
+    spawn(publish_event(
+        bus,
         format!("users.{}.groups.{}.joined", email, group_id),
         email,
+        Event::MemberJoined { group_id, user },
     ));
 
     Ok(HttpResponse::Ok().json(()))
 }
 
-#[put("/groups/{group_id}")]
+#[put("/groups/{group_id}", wrap = "from_fn(idempotency)")]
 pub async fn edit_group(
     identity: Identity,
     path: web::Path<models::GroupId>,
+    if_match: Option<web::Header<IfMatch>>,
     group: web::Json<models::Group>,
-    redis: web::Data<RedisPool>,
+    bus: web::Data<Bus>,
     pool: web::Data<DbPool>,
 ) -> Result<HttpResponse, Error> {
     let email = identity.claims().email;
-    let web::Json(group) = group;
+    let web::Json(mut group) = group;
     let group_id = path.into_inner();
 
-    crate::queries::update_group(&email, group_id, group, &pool)
+    let expected = expected_versions(if_match)?;
+
+    let updated_at = crate::queries::update_group(&email, group_id, group.clone(), expected, &pool)
         .await
         .map_err(handle_unknown_error)?;
 
-    let redis = redis.as_ref();
-    spawn(publish_topic(
-        redis.clone(),
+    let updated_at = match updated_at {
+        models::Versioned::Updated(updated_at) => updated_at,
+        models::Versioned::NotFound => return Err(ErrorNotFound("group not found")),
+        models::Versioned::Modified(_) => {
+            return Err(ErrorPreconditionFailed("group was modified"))
+        }
+    };
+
+    group.id = Some(group_id);
+    group.updated_at = Some(updated_at);
+
+    let bus = bus.as_ref();
+    spawn(publish_event(
+        bus.clone(),
         format!("groups.{}.config", group_id),
         email.clone(),
+        Event::GroupConfigChanged { group },
     ));
 
-    Ok(HttpResponse::Ok().json(()))
+    Ok(HttpResponse::Ok().insert_header(etag(updated_at)).json(()))
 }
 
 #[get("/groups/{group_id}")]
@@ -184,10 +230,12 @@ pub async fn fetch_detailed_group(
         .await
         .map_err(handle_unknown_error)?;
 
-    Ok(HttpResponse::Ok().json(&group))
+    Ok(HttpResponse::Ok()
+        .insert_header(etag(group.updated_at))
+        .json(&group))
 }
 
-#[put("/notifications")]
+#[put("/notifications", wrap = "from_fn(idempotency)")]
 pub async fn update_notifications(
     notifications_update: web::Json<models::NotificationsUpdate>,
     pool: web::Data<DbPool>,
@@ -199,88 +247,94 @@ pub async fn update_notifications(
     Ok(HttpResponse::Ok().json(()))
 }
 
-#[put("/notifications/{notification_id}")]
+#[put("/notifications/{notification_id}", wrap = "from_fn(idempotency)")]
 pub async fn update_notification(
+    identity: Identity,
     path: web::Path<i32>,
     notification_update: web::Json<models::NotificationUpdate>,
     pool: web::Data<DbPool>,
 ) -> Result<HttpResponse, Error> {
+    let email = identity.claims().email;
     let notification_id = path.into_inner();
 
-    crate::queries::update_notification(notification_id, notification_update.0, &pool)
-        .await
-        .map_err(handle_unknown_error)?;
+    let updated = crate::queries::update_notification(
+        &email,
+        notification_id,
+        notification_update.0,
+        None,
+        pool.get_ref(),
+    )
+    .await
+    .map_err(handle_unknown_error)?;
+
+    if let models::Versioned::NotFound = updated {
+        return Err(ErrorNotFound("notification not found"));
+    }
 
     Ok(HttpResponse::Ok().json(()))
 }
 
-#[put("/groups/{group_id}/memberships")]
+#[put("/groups/{group_id}/memberships", wrap = "from_fn(idempotency)")]
 pub async fn update_membership(
     identity: Identity,
     group_id: web::Path<models::GroupId>,
     membership_invitation: web::Json<models::MembershipUpdate>,
-    redis: web::Data<RedisPool>,
+    bus: web::Data<Bus>,
     pool: web::Data<DbPool>,
 ) -> Result<HttpResponse, Error> {
     let email = identity.claims().email;
     let group_id = group_id.into_inner();
 
     let web::Json(models::MembershipUpdate { status }) = membership_invitation;
+    let joined = status == models::MembershipStatus::Joined;
 
-    crate::queries::update_membership(&email, status, group_id, &pool)
+    let updated = crate::queries::update_membership(&email, status, group_id, None, pool.get_ref())
         .await
         .map_err(handle_unknown_error)?;
 
-    let redis = redis.as_ref();
-    spawn(publish_topic(
-        redis.clone(),
-        format!("groups.{}.members.{}", group_id, email),
-        email.clone(),
-    ));
+    if let models::Versioned::NotFound = updated {
+        return Err(ErrorNotFound("membership not found"));
+    }
 
-    spawn(publish_topic(
-        redis.clone(),
-        format!("users.{}.groups.{}.joined", email, group_id),
-        email,
-    ));
+    let user = crate::queries::find_user(&email, pool.get_ref())
+        .await
+        .map_err(handle_unknown_error)?;
+
+    publish_membership(bus.as_ref(), group_id, user, joined);
 
     Ok(HttpResponse::Ok().json(()))
 }
 
-#[post("/groups/{group_id}/memberships")]
+#[post("/groups/{group_id}/memberships", wrap = "from_fn(idempotency)")]
 pub async fn create_memberships(
     identity: Identity,
     group_id: web::Path<i32>,
     membership_invitation: web::Json<models::MembershipInvitation>,
     pool: web::Data<DbPool>,
-    redis: web::Data<RedisPool>,
+    bus: web::Data<Bus>,
 ) -> Result<HttpResponse, Error> {
     let email = identity.claims().email;
     let group_id = group_id.into_inner();
 
     let web::Json(models::MembershipInvitation { emails }) = membership_invitation;
 
-    crate::queries::create_membership_invites(&email, &emails, group_id, &pool)
+    let notifications = crate::queries::create_membership_invites(&email, &emails, group_id, &pool)
         .await
         .map_err(handle_unknown_error)?;
 
-    let redis = redis.as_ref();
-    for invite in emails {
-        spawn(publish_topic(
-            redis.clone(),
-            format!("users.{}.notifications", invite),
-            email.clone(),
-        ));
-    }
+    publish_notifications(bus.as_ref(), notifications, &email);
 
     Ok(HttpResponse::Ok().json(()))
 }
 
-#[delete("/groups/{group_id}/expenses/{expense_id}")]
+#[delete(
+    "/groups/{group_id}/expenses/{expense_id}",
+    wrap = "from_fn(idempotency)"
+)]
 pub async fn delete_expense(
     identity: Identity,
     path: web::Path<(models::GroupId, i32)>,
-    redis: web::Data<RedisPool>,
+    bus: web::Data<Bus>,
     pool: web::Data<DbPool>,
 ) -> Result<HttpResponse, Error> {
     let email = identity.claims().email;
@@ -288,27 +342,36 @@ pub async fn delete_expense(
 
     // TODO - check that current user is joined in group - moliva - 2024/03/21
 
-    crate::queries::delete_expense(&email, group_id, expense_id, &pool)
-        .await
-        .map_err(handle_unknown_error)?;
+    let deleted =
+        crate::queries::delete_expense(&email, group_id, expense_id, None, pool.get_ref())
+            .await
+            .map_err(handle_unknown_error)?;
+
+    if let models::Versioned::NotFound = deleted {
+        return Err(ErrorNotFound("expense not found"));
+    }
 
-    let redis = redis.as_ref();
-    spawn(publish_topic(
-        redis.clone(),
-        format!("groups.{}.expenses.{}", group_id, email),
+    let bus = bus.as_ref();
+    spawn(publish_event(
+        bus.clone(),
+        format!("groups.{}.expenses.{}", group_id, expense_id),
         email,
+        Event::ExpenseDeleted {
+            group_id,
+            expense_id,
+        },
     ));
 
     Ok(HttpResponse::Ok().json(()))
 }
 
-#[post("/groups/{group_id}/expenses")]
+#[post("/groups/{group_id}/expenses", wrap = "from_fn(idempotency)")]
 pub async fn create_expense(
     identity: Identity,
     group_id: web::Path<i32>,
     body: web::Json<models::Expense>,
     pool: web::Data<DbPool>,
-    redis: web::Data<RedisPool>,
+    bus: web::Data<Bus>,
 ) -> Result<HttpResponse, Error> {
     let email = identity.claims().email;
     let group_id = group_id.into_inner();
@@ -317,185 +380,1054 @@ pub async fn create_expense(
 
     let web::Json(expense) = body;
 
-    let split_strategy = expense.split_strategy.clone();
+    expense
+        .split_strategy
+        .validate(expense.amount)
+        .map_err(ErrorBadRequest)?;
+
+    let (expense_id, notifications) =
+        crate::queries::create_expense(&email, Some(group_id), expense, pool.get_ref())
+            .await
+            .map_err(handle_unknown_error)?;
 
-    let expense_id = crate::queries::create_expense(&email, group_id, expense, &pool)
+    let expense = crate::queries::find_expense(group_id, expense_id, pool.get_ref())
         .await
-        .map_err(handle_unknown_error)?;
+        .map_err(handle_unknown_error)?
+        .expect("created expense");
 
-    let redis = redis.as_ref();
-    spawn(publish_topic(
-        redis.clone(),
+    let bus = bus.as_ref();
+
+    spawn(publish_event(
+        bus.clone(),
         format!("groups.{}.expenses.{}", group_id, expense_id),
         email.clone(),
+        Event::ExpenseCreated { expense },
     ));
 
-    if let SplitStrategy::Payment { payer, recipient } = split_strategy {
-        spawn(lookup_and_publish(
-            pool.clone(),
-            redis.clone(),
-            payer,
-            recipient,
-            email.clone(),
+    publish_notifications(bus, notifications, &email);
+
+    Ok(HttpResponse::Ok().json(()))
+}
+
+#[post("/groups/{group_id}/expenses/batch", wrap = "from_fn(idempotency)")]
+pub async fn create_expenses(
+    identity: Identity,
+    group_id: web::Path<models::GroupId>,
+    body: web::Json<Vec<models::Expense>>,
+    pool: web::Data<DbPool>,
+    bus: web::Data<Bus>,
+) -> Result<HttpResponse, Error> {
+    let email = identity.claims().email;
+    let group_id = group_id.into_inner();
+
+    // TODO - check that current user is joined in group - moliva - 2024/03/21
+
+    let web::Json(expenses) = body;
+
+    if expenses.len() > MAX_BATCH_SIZE {
+        return Err(ErrorBadRequest(format!(
+            "batches are limited to {} expenses",
+            MAX_BATCH_SIZE
+        )));
+    }
+
+    let mut results = expenses
+        .iter()
+        .map(|e| models::BatchItemResult {
+            id: None,
+            error: e.split_strategy.validate(e.amount).err(),
+        })
+        .collect::<Vec<_>>();
+
+    let valid = expenses
+        .into_iter()
+        .zip(results.iter())
+        .filter(|(_, r)| r.error.is_none())
+        .map(|(e, _)| e)
+        .collect::<Vec<_>>();
+
+    if valid.is_empty() {
+        return Ok(HttpResponse::BadRequest().json(&results));
+    }
+
+    let (ids, notifications) = crate::queries::create_expenses(&email, group_id, &valid, &pool)
+        .await
+        .map_err(handle_unknown_error)?;
+
+    for (result, id) in results
+        .iter_mut()
+        .filter(|r| r.error.is_none())
+        .zip(ids.iter())
+    {
+        result.id = Some(*id);
+    }
+
+    // a single event for the whole batch
+    let bus = bus.as_ref();
+    spawn(publish_event(
+        bus.clone(),
+        format!("groups.{}.expenses.batch", group_id),
+        email.clone(),
+        Event::ExpensesCreated {
+            group_id,
+            expense_ids: ids.clone(),
+        },
+    ));
+
+    publish_notifications(bus, notifications, &email);
+
+    Ok(HttpResponse::Ok().json(&results))
+}
+
+/// Applies the mutations queued by a client while offline, in order. Mutations already applied
+/// (retries) get their original outcome back.
+#[post("/mutations", wrap = "from_fn(idempotency)")]
+pub async fn apply_mutations(
+    identity: Identity,
+    body: web::Json<Vec<models::Mutation>>,
+    pool: web::Data<DbPool>,
+    bus: web::Data<Bus>,
+) -> Result<HttpResponse, Error> {
+    let email = identity.claims().email;
+    let web::Json(mutations) = body;
+
+    if mutations.len() > MAX_BATCH_SIZE {
+        return Err(ErrorBadRequest(format!(
+            "batches are limited to {} mutations",
+            MAX_BATCH_SIZE
+        )));
+    }
+
+    // versions of the expenses after the mutations of the batch, for the ones omitting them
+    let mut versions = HashMap::new();
+    let mut results = Vec::with_capacity(mutations.len());
+
+    for models::Mutation { id, change } in mutations {
+        // the claim, the changes and the outcome commit together, a failure in between leaves
+        // the mutation to be applied by the retry
+        let mut tx = pool.begin().await.map_err(handle_unknown_error)?;
+
+        let claimed = crate::queries::claim_mutation(&email, id, &mut tx)
+            .await
+            .map_err(handle_unknown_error)?;
+
+        let (outcome, effect) = match claimed {
+            Some(outcome) => (outcome, MutationEffect::None),
+            None => {
+                let (outcome, effect) = apply_mutation(&email, change, &versions, &mut tx)
+                    .await
+                    .map_err(handle_unknown_error)?;
+
+                crate::queries::complete_mutation(&email, id, &outcome, &mut *tx)
+                    .await
+                    .map_err(handle_unknown_error)?;
+
+                (outcome, effect)
+            }
+        };
+
+        tx.commit().await.map_err(handle_unknown_error)?;
+
+        publish_mutation(bus.as_ref(), effect, &email);
+
+        if let models::MutationOutcome::Applied {
+            expense_id: Some(expense_id),
+            version,
+        } = &outcome
+        {
+            versions.insert(*expense_id, *version);
+        }
+
+        results.push(models::MutationResult { id, outcome });
+    }
+
+    Ok(HttpResponse::Ok().json(&results))
+}
+
+/// Changes of an applied mutation, published once they are committed.
+enum MutationEffect {
+    None,
+    ExpenseCreated {
+        group_id: models::GroupId,
+        expense: models::Expense,
+        notifications: Vec<models::CreatedNotification>,
+    },
+    ExpenseUpdated {
+        group_id: models::GroupId,
+        expense: models::Expense,
+    },
+    ExpenseDeleted {
+        group_id: models::GroupId,
+        expense_id: models::ExpenseId,
+    },
+    MembershipUpdated {
+        group_id: models::GroupId,
+        user: models::User,
+        joined: bool,
+    },
+}
+
+async fn apply_mutation(
+    email: &str,
+    change: models::MutationKind,
+    versions: &HashMap<models::ExpenseId, chrono::DateTime<chrono::Utc>>,
+    conn: &mut PgConnection,
+) -> Result<(models::MutationOutcome, MutationEffect), sqlx::Error> {
+    use models::MutationOutcome::{Applied, Conflict, Rejected};
+
+    match change {
+        models::MutationKind::CreateExpense { group_id, expense } => {
+            if let Err(error) = expense.split_strategy.validate(expense.amount) {
+                return Ok((Rejected { error }, MutationEffect::None));
+            }
+
+            let (expense_id, notifications) =
+                crate::queries::create_expense(email, Some(group_id), expense, &mut *conn).await?;
+
+            let expense = crate::queries::find_expense(group_id, expense_id, &mut *conn)
+                .await?
+                .expect("created expense");
+            let version = expense.updated_at.expect("expense version");
+
+            Ok((
+                Applied {
+                    expense_id: Some(expense_id),
+                    version,
+                },
+                MutationEffect::ExpenseCreated {
+                    group_id,
+                    expense,
+                    notifications,
+                },
+            ))
+        }
+        models::MutationKind::UpdateExpense {
+            group_id,
+            expense_id,
+            base_version,
+            expense,
+        } => {
+            if let Err(error) = expense.split_strategy.validate(expense.amount) {
+                return Ok((Rejected { error }, MutationEffect::None));
+            }
+
+            let Some(expense_id) = resolve_expense(email, expense_id, &mut *conn).await? else {
+                let error = "unknown expense".to_owned();
+                return Ok((Rejected { error }, MutationEffect::None));
+            };
+
+            let Some(base_version) = base_version.or_else(|| versions.get(&expense_id).copied())
+            else {
+                let error = "missing base version".to_owned();
+                return Ok((Rejected { error }, MutationEffect::None));
+            };
+
+            let updated = crate::queries::update_expense(
+                email,
+                group_id,
+                expense_id,
+                expense,
+                Some(vec![base_version]),
+                &mut *conn,
+            )
+            .await?;
+
+            Ok(match updated {
+                models::Versioned::Updated(expense) => {
+                    let version = expense.updated_at.expect("expense version");
+
+                    (
+                        Applied {
+                            expense_id: Some(expense_id),
+                            version,
+                        },
+                        MutationEffect::ExpenseUpdated { group_id, expense },
+                    )
+                }
+                models::Versioned::NotFound => (Conflict { version: None }, MutationEffect::None),
+                models::Versioned::Modified(version) => (
+                    Conflict {
+                        version: Some(version),
+                    },
+                    MutationEffect::None,
+                ),
+            })
+        }
+        models::MutationKind::DeleteExpense {
+            group_id,
+            expense_id,
+            base_version,
+        } => {
+            let Some(expense_id) = resolve_expense(email, expense_id, &mut *conn).await? else {
+                let error = "unknown expense".to_owned();
+                return Ok((Rejected { error }, MutationEffect::None));
+            };
+
+            let Some(base_version) = base_version.or_else(|| versions.get(&expense_id).copied())
+            else {
+                let error = "missing base version".to_owned();
+                return Ok((Rejected { error }, MutationEffect::None));
+            };
+
+            let deleted = crate::queries::delete_expense(
+                email,
+                group_id,
+                expense_id,
+                Some(vec![base_version]),
+                &mut *conn,
+            )
+            .await?;
+
+            Ok(match deleted {
+                models::Versioned::Updated(version) => (
+                    Applied {
+                        expense_id: Some(expense_id),
+                        version,
+                    },
+                    MutationEffect::ExpenseDeleted {
+                        group_id,
+                        expense_id,
+                    },
+                ),
+                models::Versioned::NotFound => (Conflict { version: None }, MutationEffect::None),
+                models::Versioned::Modified(version) => (
+                    Conflict {
+                        version: Some(version),
+                    },
+                    MutationEffect::None,
+                ),
+            })
+        }
+        models::MutationKind::UpdateMembership {
+            group_id,
+            status,
+            base_version,
+        } => {
+            let joined = status == models::MembershipStatus::Joined;
+
+            let updated = crate::queries::update_membership(
+                email,
+                status,
+                group_id,
+                Some(vec![base_version]),
+                &mut *conn,
+            )
+            .await?;
+
+            Ok(match updated {
+                models::Versioned::Updated(version) => {
+                    let user = crate::queries::find_user(email, &mut *conn).await?;
+
+                    (
+                        Applied {
+                            expense_id: None,
+                            version,
+                        },
+                        MutationEffect::MembershipUpdated {
+                            group_id,
+                            user,
+                            joined,
+                        },
+                    )
+                }
+                models::Versioned::NotFound => (Conflict { version: None }, MutationEffect::None),
+                models::Versioned::Modified(version) => (
+                    Conflict {
+                        version: Some(version),
+                    },
+                    MutationEffect::None,
+                ),
+            })
+        }
+        models::MutationKind::UpdateNotification {
+            notification_id,
+            status,
+            base_version,
+        } => {
+            let updated = crate::queries::update_notification(
+                email,
+                notification_id,
+                models::NotificationUpdate { status },
+                Some(vec![base_version]),
+                &mut *conn,
+            )
+            .await?;
+
+            let outcome = match updated {
+                models::Versioned::Updated(version) => Applied {
+                    expense_id: None,
+                    version,
+                },
+                models::Versioned::NotFound => Conflict { version: None },
+                models::Versioned::Modified(version) => Conflict {
+                    version: Some(version),
+                },
+            };
+
+            Ok((outcome, MutationEffect::None))
+        }
+    }
+}
+
+fn publish_mutation(bus: &Bus, effect: MutationEffect, email: &str) {
+    match effect {
+        MutationEffect::None => {}
+        MutationEffect::ExpenseCreated {
+            group_id,
+            expense,
+            notifications,
+        } => {
+            spawn(publish_event(
+                bus.clone(),
+                format!(
+                    "groups.{}.expenses.{}",
+                    group_id,
+                    expense.id.expect("expense id")
+                ),
+                email.to_owned(),
+                Event::ExpenseCreated { expense },
+            ));
+
+            publish_notifications(bus, notifications, email);
+        }
+        MutationEffect::ExpenseUpdated { group_id, expense } => {
+            spawn(publish_event(
+                bus.clone(),
+                format!(
+                    "groups.{}.expenses.{}",
+                    group_id,
+                    expense.id.expect("expense id")
+                ),
+                email.to_owned(),
+                Event::ExpenseUpdated { expense },
+            ));
+        }
+        MutationEffect::ExpenseDeleted {
+            group_id,
+            expense_id,
+        } => {
+            spawn(publish_event(
+                bus.clone(),
+                format!("groups.{}.expenses.{}", group_id, expense_id),
+                email.to_owned(),
+                Event::ExpenseDeleted {
+                    group_id,
+                    expense_id,
+                },
+            ));
+        }
+        MutationEffect::MembershipUpdated {
+            group_id,
+            user,
+            joined,
+        } => publish_membership(bus, group_id, user, joined),
+    }
+}
+
+/// Id of the expense, looking up the ones created by previous mutations.
+async fn resolve_expense(
+    email: &str,
+    expense: models::ExpenseRef,
+    conn: &mut PgConnection,
+) -> Result<Option<models::ExpenseId>, sqlx::Error> {
+    match expense {
+        models::ExpenseRef::Id(expense_id) => Ok(Some(expense_id)),
+        models::ExpenseRef::Created(mutation_id) => {
+            let outcome = crate::queries::find_mutation_outcome(email, mutation_id, conn).await?;
+
+            Ok(match outcome {
+                Some(models::MutationOutcome::Applied { expense_id, .. }) => expense_id,
+                _ => None,
+            })
+        }
+    }
+}
+
+#[post("/groups/{group_id}/budgets", wrap = "from_fn(idempotency)")]
+pub async fn create_budget(
+    identity: Identity,
+    group_id: web::Path<models::GroupId>,
+    body: web::Json<models::Budget>,
+    pool: web::Data<DbPool>,
+    bus: web::Data<Bus>,
+) -> Result<HttpResponse, Error> {
+    let email = identity.claims().email;
+    let group_id = group_id.into_inner();
+
+    // TODO - check that current user is joined in group - moliva - 2024/03/21
+
+    let web::Json(budget) = body;
+
+    if budget.amount <= 0f64 || budget.threshold <= 0f64 {
+        return Err(ErrorBadRequest(
+            "budget amount and threshold must be positive",
         ));
     }
 
+    crate::queries::create_budget(&email, group_id, budget, &pool)
+        .await
+        .map_err(handle_unknown_error)?;
+
+    let bus = bus.as_ref();
+    spawn(publish_event(
+        bus.clone(),
+        format!("groups.{}.budgets", group_id),
+        email,
+        Event::BudgetsChanged { group_id },
+    ));
+
+    Ok(HttpResponse::Ok().json(()))
+}
+
+#[get("/groups/{group_id}/budgets")]
+pub async fn fetch_budgets(
+    _identity: Identity,
+    group_id: web::Path<models::GroupId>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, Error> {
+    let group_id = group_id.into_inner();
+
+    // TODO - check that current user is joined in group - moliva - 2024/03/21
+
+    let budgets =
+        crate::queries::find_budget_statuses(group_id, chrono::Utc::now(), pool.get_ref())
+            .await
+            .map_err(handle_unknown_error)?;
+
+    Ok(HttpResponse::Ok().json(&budgets))
+}
+
+#[delete(
+    "/groups/{group_id}/budgets/{budget_id}",
+    wrap = "from_fn(idempotency)"
+)]
+pub async fn delete_budget(
+    identity: Identity,
+    path: web::Path<(models::GroupId, models::BudgetId)>,
+    pool: web::Data<DbPool>,
+    bus: web::Data<Bus>,
+) -> Result<HttpResponse, Error> {
+    let email = identity.claims().email;
+    let (group_id, budget_id) = path.into_inner();
+
+    // TODO - check that current user is joined in group - moliva - 2024/03/21
+
+    crate::queries::delete_budget(group_id, budget_id, &pool)
+        .await
+        .map_err(handle_unknown_error)?;
+
+    let bus = bus.as_ref();
+    spawn(publish_event(
+        bus.clone(),
+        format!("groups.{}.budgets", group_id),
+        email,
+        Event::BudgetsChanged { group_id },
+    ));
+
     Ok(HttpResponse::Ok().json(()))
 }
 
 #[get("/groups/{group_id}/balances")]
 pub async fn fetch_balances(
+    _identity: Identity,
+    group_id: web::Path<i32>,
+    query: web::Query<models::BalancesQuery>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, Error> {
+    let group_id = group_id.into_inner();
+    let web::Query(models::BalancesQuery { as_of }) = query;
+
+    // TODO - check that current user is joined in group - moliva - 2024/03/21
+
+    let memberships = crate::queries::find_memberships(group_id, pool.get_ref())
+        .await
+        .map_err(handle_unknown_error)?;
+    let user_ids = memberships.into_iter().map(|m| m.user_id);
+
+    let balances = if let Some(as_of) = as_of {
+        // past balances are not materialized, they are computed from the expenses up to that date
+        let expenses = crate::queries::find_expenses_as_of(group_id, as_of, &pool)
+            .await
+            .map_err(handle_unknown_error)?;
+
+        compute_balances(user_ids, &expenses)
+    } else {
+        let pairs = crate::queries::find_pair_balances(group_id, pool.get_ref())
+            .await
+            .map_err(handle_unknown_error)?;
+
+        balances_from_pairs(user_ids, pairs)
+    };
+
+    Ok(HttpResponse::Ok().json(balances.values().collect::<Vec<_>>()))
+}
+
+#[get("/groups/{group_id}/balances/history")]
+pub async fn fetch_balance_history(
     identity: Identity,
     group_id: web::Path<i32>,
+    query: web::Query<models::BalanceHistoryQuery>,
     pool: web::Data<DbPool>,
 ) -> Result<HttpResponse, Error> {
     let email = identity.claims().email;
     let group_id = group_id.into_inner();
+    let web::Query(models::BalanceHistoryQuery { bucket }) = query;
 
     // TODO - check that current user is joined in group - moliva - 2024/03/21
 
-    let expenses = crate::queries::find_expenses(&email, group_id, &pool)
+    let mut expenses =
+        crate::queries::find_expenses(&email, group_id, models::ExpenseStatus::Published, &pool)
+            .await
+            .map_err(handle_unknown_error)?;
+    expenses.reverse();
+
+    let memberships = crate::queries::find_memberships(group_id, pool.get_ref())
         .await
         .map_err(handle_unknown_error)?;
 
-    let memberships = crate::queries::find_memberships(group_id, &pool)
+    let history = compute_balance_history(
+        memberships.into_iter().map(|m| m.user_id),
+        &expenses,
+        bucket,
+    );
+
+    Ok(HttpResponse::Ok().json(&history))
+}
+
+#[get("/groups/{group_id}/stats")]
+pub async fn fetch_stats(
+    _identity: Identity,
+    group_id: web::Path<i32>,
+    query: web::Query<models::StatsQuery>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, Error> {
+    let group_id = group_id.into_inner();
+    let web::Query(models::StatsQuery { from, to }) = query;
+
+    // TODO - check that current user is joined in group - moliva - 2024/03/21
+
+    let expenses = crate::queries::find_expenses_between(group_id, from, to, &pool)
         .await
         .map_err(handle_unknown_error)?;
 
-    let mut balances =
-        HashMap::<models::UserId, models::Balance>::from_iter(memberships.into_iter().map(|m| {
-            (
-                m.user_id.clone(),
-                Balance {
-                    user_id: m.user_id,
-                    total: HashMap::default(),
-                    owes: HashMap::default(),
-                },
-            )
-        }));
+    let stats = compute_group_stats(&expenses);
 
-    // TODO - simplified balances - moliva - 2024/03/22
-
-    for expense in expenses {
-        match expense.split_strategy {
-            models::SplitStrategy::Equally {
-                payer,
-                split_between,
-            } => {
-                let roman = expense.amount / split_between.len() as f64;
-
-                for ower in split_between {
-                    if ower == payer {
-                        // nothing to do here
-                        continue;
-                    }
-
-                    // add bill to ower in relation to payer
-                    balances.entry(ower.clone()).and_modify(|balance| {
-                        balance
-                            .total
-                            .entry(expense.currency_id)
-                            .and_modify(|a| *a += roman)
-                            .or_insert(roman);
-
-                        balance
-                            .owes
-                            .entry(payer.clone())
-                            .and_modify(|debts| {
-                                debts
-                                    .entry(expense.currency_id)
-                                    .and_modify(|a| *a += roman)
-                                    .or_insert(roman);
-                            })
-                            .or_insert_with(|| {
-                                let mut debts = HashMap::default();
-                                debts.insert(expense.currency_id, roman);
-                                debts
-                            });
-                    });
-
-                    // decrease bill from payer in realtion to ower
-                    balances.entry(payer.clone()).and_modify(|balance| {
-                        balance
-                            .total
-                            .entry(expense.currency_id)
-                            .and_modify(|a| *a -= roman)
-                            .or_insert(-roman);
-
-                        balance
-                            .owes
-                            .entry(ower.clone())
-                            .and_modify(|debts| {
-                                debts
-                                    .entry(expense.currency_id)
-                                    .and_modify(|a| *a -= roman)
-                                    .or_insert(-roman);
-                            })
-                            .or_insert_with(|| {
-                                let mut debts = HashMap::default();
-                                debts.insert(expense.currency_id, -roman);
-                                debts
-                            });
-                    });
-                }
-            }
-            models::SplitStrategy::Payment { payer, recipient } => {
-                balances.entry(payer.clone()).and_modify(|balance| {
-                    balance
-                        .total
-                        .entry(expense.currency_id)
-                        .and_modify(|a| *a -= expense.amount)
-                        .or_insert(-expense.amount);
-
-                    balance
-                        .owes
-                        .entry(recipient.clone())
-                        .and_modify(|debts| {
-                            debts
-                                .entry(expense.currency_id)
-                                .and_modify(|a| *a -= expense.amount)
-                                .or_insert(-expense.amount);
-                        })
-                        .or_insert_with(|| {
-                            let mut debts = HashMap::default();
-                            debts.insert(expense.currency_id, -expense.amount);
-                            debts
-                        });
-                });
-
-                balances.entry(recipient.clone()).and_modify(|balance| {
-                    balance
-                        .total
-                        .entry(expense.currency_id)
-                        .and_modify(|a| *a += expense.amount)
-                        .or_insert(expense.amount);
-
-                    balance
-                        .owes
-                        .entry(payer.clone())
-                        .and_modify(|debts| {
-                            debts
-                                .entry(expense.currency_id)
-                                .and_modify(|a| *a += expense.amount)
-                                .or_insert(expense.amount);
-                        })
-                        .or_insert_with(|| {
-                            let mut debts = HashMap::default();
-                            debts.insert(expense.currency_id, expense.amount);
-                            debts
-                        });
-                });
+    Ok(HttpResponse::Ok().json(&stats))
+}
+
+#[get("/groups/{group_id}/export")]
+pub async fn export_group(
+    identity: Identity,
+    group_id: web::Path<models::GroupId>,
+    query: web::Query<ExportQuery>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, Error> {
+    let email = identity.claims().email;
+    let group_id = group_id.into_inner();
+    let web::Query(ExportQuery {
+        format,
+        include_deleted,
+    }) = query;
+
+    let group = crate::queries::find_group(&email, group_id, &pool)
+        .await
+        .map_err(handle_unknown_error)?;
+
+    // the balances of the footer are read from the same snapshot as the expenses
+    let mut tx = crate::queries::begin_snapshot(&pool)
+        .await
+        .map_err(handle_unknown_error)?;
+
+    let pairs = crate::queries::find_pair_balances(group_id, &mut *tx)
+        .await
+        .map_err(handle_unknown_error)?;
+
+    let memberships = crate::queries::find_memberships(group_id, &mut *tx)
+        .await
+        .map_err(handle_unknown_error)?;
+
+    // former members might still be part of old expenses
+    let mut participants = crate::queries::find_ledger_participants(group_id, &mut tx)
+        .await
+        .map_err(handle_unknown_error)?;
+    participants.extend(memberships.iter().map(|m| m.user_id.clone()));
+    let participants = participants.into_iter().collect::<Vec<_>>();
+
+    let users = crate::queries::find_users(&participants, &mut *tx)
+        .await
+        .map_err(handle_unknown_error)?;
+
+    let balances = balances_from_pairs(memberships.into_iter().map(|m| m.user_id), pairs);
+
+    let currencies = crate::queries::find_currencies(&pool)
+        .await
+        .map_err(handle_unknown_error)?;
+
+    let context = ExportContext::new(users, currencies);
+    let header = ExportHeader {
+        group_id,
+        name: group.name,
+        exported_at: chrono::Utc::now(),
+    };
+    let balances = context.balances(balances);
+
+    // the expenses are encoded as they are read, the channel holds the response back from
+    // reading further when the client is slow
+    let (mut sender, chunks) = mpsc::channel::<Result<Bytes, Error>>(EXPORT_BUFFER);
+
+    spawn(async move {
+        if sender.send(Ok(format.header(&header))).await.is_err() {
+            return;
+        }
+
+        let mut expenses = crate::queries::stream_ledger(group_id, include_deleted, &mut *tx);
+        let mut first = true;
+
+        while let Some(expense) = expenses.next().await {
+            let chunk = expense
+                .map(|e| format.expense(&context.expense(e), first))
+                .map_err(handle_unknown_error);
+            first = false;
+
+            // errors end the response halfway, the client gets a truncated file
+            let failed = chunk.is_err();
+            if sender.send(chunk).await.is_err() || failed {
+                return;
             }
         }
+
+        let _ = sender.send(Ok(format.footer(&balances))).await;
+    });
+
+    let (content_type, extension) = match format {
+        ExportFormat::Json => ("application/json", "json"),
+        ExportFormat::Csv => ("text/csv", "csv"),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(ContentDisposition::attachment(format!(
+            "group-{}.{}",
+            group_id, extension
+        )))
+        .streaming(chunks))
+}
+
+#[post("/groups/{group_id}/import", wrap = "from_fn(idempotency)")]
+pub async fn import_splitwise(
+    identity: Identity,
+    group_id: web::Path<models::GroupId>,
+    body: web::Json<SplitwiseImport>,
+    pool: web::Data<DbPool>,
+    bus: web::Data<Bus>,
+) -> Result<HttpResponse, Error> {
+    let email = identity.claims().email;
+    let group_id = group_id.into_inner();
+    let web::Json(import) = body;
+
+    let group = crate::queries::find_group(&email, group_id, &pool)
+        .await
+        .map_err(handle_unknown_error)?;
+
+    let currencies = crate::queries::find_currencies(&pool)
+        .await
+        .map_err(handle_unknown_error)?;
+
+    let members = group
+        .members
+        .into_iter()
+        .map(|m| m.user)
+        .collect::<Vec<_>>();
+
+    let mut report = parse_splitwise(&import, &members, &currencies);
+
+    if !report.is_valid() {
+        return Ok(HttpResponse::BadRequest().json(&report));
     }
 
-    Ok(HttpResponse::Ok().json(balances.values().collect::<Vec<_>>()))
+    if !report.dry_run && !report.expenses.is_empty() {
+        let ids = crate::queries::import_expenses(&email, group_id, &report.expenses, &pool)
+            .await
+            .map_err(handle_unknown_error)?;
+        report.imported = true;
+
+        let bus = bus.as_ref();
+        spawn(publish_event(
+            bus.clone(),
+            format!("groups.{}.expenses.import", group_id),
+            email,
+            Event::ExpensesCreated {
+                group_id,
+                expense_ids: ids,
+            },
+        ));
+    }
+
+    Ok(HttpResponse::Ok().json(&report))
+}
+
+#[post("/groups/{group_id}/statements", wrap = "from_fn(idempotency)")]
+pub async fn import_statement(
+    identity: Identity,
+    group_id: web::Path<models::GroupId>,
+    body: web::Json<StatementImport>,
+    pool: web::Data<DbPool>,
+    bus: web::Data<Bus>,
+) -> Result<HttpResponse, Error> {
+    let email = identity.claims().email;
+    let group_id = group_id.into_inner();
+    let web::Json(import) = body;
+
+    let group = crate::queries::find_group(&email, group_id, &pool)
+        .await
+        .map_err(handle_unknown_error)?;
+
+    let transactions = parse_statement(
+        import.format,
+        &import.content,
+        import.date_format.as_deref(),
+    )
+    .map_err(ErrorBadRequest)?;
+
+    let (debits, credits): (Vec<_>, Vec<_>) =
+        transactions.into_iter().partition(|t| t.amount < 0f64);
+
+    let mut report = StatementReport {
+        drafts: Vec::default(),
+        duplicates: Vec::default(),
+        skipped: credits.len(),
+    };
+
+    let (Some(from), Some(to)) = (
+        debits.iter().map(|t| t.date).min(),
+        debits.iter().map(|t| t.date).max(),
+    ) else {
+        return Ok(HttpResponse::Ok().json(&report));
+    };
+
+    let recorded = crate::queries::find_recorded_expenses(
+        group_id,
+        from.and_hms_opt(0, 0, 0).expect("midnight").and_utc(),
+        to.and_hms_opt(23, 59, 59).expect("end of day").and_utc(),
+        &pool,
+    )
+    .await
+    .map_err(handle_unknown_error)?;
+
+    let user = crate::queries::find_user(&email, pool.get_ref())
+        .await
+        .map_err(handle_unknown_error)?;
+
+    // drafts are split equally among the joined members until reviewed
+    let split_between = group
+        .members
+        .into_iter()
+        .filter(|m| m.status == models::MembershipStatus::Joined)
+        .map(|m| m.user.id)
+        .collect::<Vec<_>>();
+    let currency_id = import.currency_id.unwrap_or(group.default_currency_id);
+
+    for transaction in debits {
+        if is_duplicate(&transaction, &recorded) {
+            report.duplicates.push(transaction);
+            continue;
+        }
+
+        report.drafts.push(models::Expense {
+            id: None,
+            group_id: Some(group_id),
+            deleted: false,
+            draft: true,
+            description: transaction.description,
+            currency_id,
+            amount: -transaction.amount,
+            date: transaction
+                .date
+                .and_hms_opt(0, 0, 0)
+                .expect("midnight")
+                .and_utc(),
+            split_strategy: SplitStrategy::Equally {
+                payer: user.id.clone(),
+                split_between: split_between.clone(),
+            },
+            category: None,
+            created_by_id: Some(user.id.clone()),
+            created_at: None,
+            updated_by_id: Some(user.id.clone()),
+            updated_at: None,
+        });
+    }
+
+    if !report.drafts.is_empty() {
+        let ids = crate::queries::import_expenses(&email, group_id, &report.drafts, &pool)
+            .await
+            .map_err(handle_unknown_error)?;
+
+        for (draft, id) in report.drafts.iter_mut().zip(ids.iter()) {
+            draft.id = Some(*id);
+        }
+
+        let bus = bus.as_ref();
+        spawn(publish_event(
+            bus.clone(),
+            format!("groups.{}.expenses.drafts", group_id),
+            email,
+            Event::ExpensesCreated {
+                group_id,
+                expense_ids: ids,
+            },
+        ));
+    }
+
+    Ok(HttpResponse::Ok().json(&report))
+}
+
+#[post("/groups/{group_id}/drafts/confirm", wrap = "from_fn(idempotency)")]
+pub async fn confirm_drafts(
+    identity: Identity,
+    group_id: web::Path<models::GroupId>,
+    body: web::Json<ConfirmDrafts>,
+    pool: web::Data<DbPool>,
+    bus: web::Data<Bus>,
+) -> Result<HttpResponse, Error> {
+    let email = identity.claims().email;
+    let group_id = group_id.into_inner();
+    let web::Json(confirm) = body;
+
+    // TODO - check that current user is joined in group - moliva - 2024/03/21
+
+    let (expenses, notifications) =
+        crate::queries::publish_drafts(&email, group_id, &confirm.expense_ids, &pool)
+            .await
+            .map_err(handle_unknown_error)?;
+
+    if !expenses.is_empty() {
+        notify_published(bus.as_ref(), group_id, &expenses, notifications, &email);
+    }
+
+    Ok(HttpResponse::Ok().json(&expenses))
+}
+
+#[post(
+    "/groups/{group_id}/expenses/{expense_id}/publish",
+    wrap = "from_fn(idempotency)"
+)]
+pub async fn publish_expense(
+    identity: Identity,
+    path: web::Path<(models::GroupId, models::ExpenseId)>,
+    pool: web::Data<DbPool>,
+    bus: web::Data<Bus>,
+) -> Result<HttpResponse, Error> {
+    let email = identity.claims().email;
+    let (group_id, expense_id) = path.into_inner();
+
+    // TODO - check that current user is joined in group - moliva - 2024/03/21
+
+    let (expenses, notifications) =
+        crate::queries::publish_drafts(&email, group_id, &[expense_id], &pool)
+            .await
+            .map_err(handle_unknown_error)?;
+
+    if expenses.is_empty() {
+        return Err(ErrorNotFound("draft not found"));
+    }
+
+    notify_published(bus.as_ref(), group_id, &expenses, notifications, &email);
+
+    Ok(HttpResponse::Ok().json(&expenses[0]))
+}
+
+#[put(
+    "/groups/{group_id}/expenses/{expense_id}",
+    wrap = "from_fn(idempotency)"
+)]
+pub async fn update_expense(
+    identity: Identity,
+    path: web::Path<(models::GroupId, models::ExpenseId)>,
+    if_match: Option<web::Header<IfMatch>>,
+    body: web::Json<models::Expense>,
+    pool: web::Data<DbPool>,
+    bus: web::Data<Bus>,
+) -> Result<HttpResponse, Error> {
+    let email = identity.claims().email;
+    let (group_id, expense_id) = path.into_inner();
+
+    // TODO - check that current user is joined in group - moliva - 2024/03/21
+
+    let web::Json(expense) = body;
+
+    expense
+        .split_strategy
+        .validate(expense.amount)
+        .map_err(ErrorBadRequest)?;
+
+    let expected = expected_versions(if_match)?;
+
+    let expense = crate::queries::update_expense(
+        &email,
+        group_id,
+        expense_id,
+        expense,
+        expected,
+        pool.get_ref(),
+    )
+    .await
+    .map_err(handle_unknown_error)?;
+
+    let expense = match expense {
+        models::Versioned::Updated(expense) => expense,
+        models::Versioned::NotFound => return Err(ErrorNotFound("expense not found")),
+        models::Versioned::Modified(_) => {
+            return Err(ErrorPreconditionFailed("expense was modified"))
+        }
+    };
+
+    let bus = bus.as_ref();
+    spawn(publish_event(
+        bus.clone(),
+        format!("groups.{}.expenses.{}", group_id, expense_id),
+        email,
+        Event::ExpenseUpdated {
+            expense: expense.clone(),
+        },
+    ));
+
+    let mut response = HttpResponse::Ok();
+    if let Some(updated_at) = expense.updated_at {
+        response.insert_header(etag(updated_at));
+    }
+
+    Ok(response.json(&expense))
+}
+
+#[get("/groups/{group_id}/expenses/{expense_id}")]
+pub async fn fetch_expense(
+    _identity: Identity,
+    path: web::Path<(models::GroupId, models::ExpenseId)>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, Error> {
+    let (group_id, expense_id) = path.into_inner();
+
+    // TODO - check that current user is joined in group - moliva - 2024/03/21
+
+    let expense = crate::queries::find_expense(group_id, expense_id, pool.get_ref())
+        .await
+        .map_err(handle_unknown_error)?
+        .ok_or_else(|| ErrorNotFound("expense not found"))?;
+
+    let mut response = HttpResponse::Ok();
+    if let Some(updated_at) = expense.updated_at {
+        response.insert_header(etag(updated_at));
+    }
+
+    Ok(response.json(&expense))
 }
 
 #[get("/groups/{group_id}/expenses")]
 pub async fn fetch_expenses(
     identity: Identity,
     group_id: web::Path<i32>,
+    query: web::Query<models::ExpensesQuery>,
     pool: web::Data<DbPool>,
 ) -> Result<HttpResponse, Error> {
     let email = identity.claims().email;
@@ -503,55 +1435,230 @@ pub async fn fetch_expenses(
 
     // TODO - check that current user is joined in group - moliva - 2024/03/21
 
-    let expenses = crate::queries::find_expenses(&email, group_id, &pool)
+    let expenses = crate::queries::find_expenses(&email, group_id, query.status, &pool)
         .await
         .map_err(handle_unknown_error)?;
 
     Ok(HttpResponse::Ok().json(&expenses))
 }
 
+#[post("/expenses", wrap = "from_fn(idempotency)")]
+pub async fn create_direct_expense(
+    identity: Identity,
+    body: web::Json<models::Expense>,
+    pool: web::Data<DbPool>,
+    bus: web::Data<Bus>,
+) -> Result<HttpResponse, Error> {
+    let email = identity.claims().email;
+
+    let web::Json(expense) = body;
+
+    expense
+        .split_strategy
+        .validate(expense.amount)
+        .map_err(ErrorBadRequest)?;
+
+    let user = crate::queries::find_user(&email, pool.get_ref())
+        .await
+        .map_err(handle_unknown_error)?;
+
+    let participants = expense.split_strategy.participants();
+    if participants.len() < 2 || !participants.contains(&user.id) {
+        return Err(ErrorBadRequest(
+            "direct expenses are shared between the current user and someone else",
+        ));
+    }
+
+    if expense.draft {
+        return Err(ErrorBadRequest("direct expenses cannot be drafts"));
+    }
+
+    let known = crate::queries::find_users(&participants, pool.get_ref())
+        .await
+        .map_err(handle_unknown_error)?;
+    if known.len() != participants.len() {
+        return Err(ErrorBadRequest("unknown participants"));
+    }
+
+    let (_, notifications) = crate::queries::create_direct_expense(&email, expense, &pool)
+        .await
+        .map_err(handle_unknown_error)?;
+
+    publish_notifications(bus.as_ref(), notifications, &email);
+
+    Ok(HttpResponse::Ok().json(()))
+}
+
+#[get("/expenses")]
+pub async fn fetch_direct_expenses(
+    identity: Identity,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, Error> {
+    let email = identity.claims().email;
+
+    let expenses = crate::queries::find_direct_expenses(&email, &pool)
+        .await
+        .map_err(handle_unknown_error)?;
+
+    Ok(HttpResponse::Ok().json(&expenses))
+}
+
+#[get("/friends/balances")]
+pub async fn fetch_friend_balances(
+    identity: Identity,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, Error> {
+    let email = identity.claims().email;
+
+    let user = crate::queries::find_user(&email, pool.get_ref())
+        .await
+        .map_err(handle_unknown_error)?;
+
+    let expenses = crate::queries::find_direct_expenses(&email, &pool)
+        .await
+        .map_err(handle_unknown_error)?;
+
+    let participants = expenses
+        .iter()
+        .flat_map(|e| e.split_strategy.participants())
+        .chain([user.id.clone()])
+        .collect::<HashSet<_>>();
+
+    let mut balances = compute_balances(participants, &expenses);
+    let balance = balances.remove(&user.id).expect("current user balance");
+
+    Ok(HttpResponse::Ok().json(&balance))
+}
+
+#[get("/me/balances")]
+pub async fn fetch_my_balances(
+    identity: Identity,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, Error> {
+    let email = identity.claims().email;
+
+    let user = crate::queries::find_user(&email, pool.get_ref())
+        .await
+        .map_err(handle_unknown_error)?;
+
+    let memberships = crate::queries::find_joined_memberships(&email, &pool)
+        .await
+        .map_err(handle_unknown_error)?;
+
+    let expenses = crate::queries::find_joined_expenses(&email, &pool)
+        .await
+        .map_err(handle_unknown_error)?;
+
+    let direct_expenses = crate::queries::find_direct_expenses(&email, &pool)
+        .await
+        .map_err(handle_unknown_error)?;
+
+    let balances = compute_friend_balances(&user.id, memberships, expenses, direct_expenses);
+
+    Ok(HttpResponse::Ok().json(&balances))
+}
+
 // *****************************************************************************************************
 // *************** Topic utils ***************
 // *****************************************************************************************************
 
-async fn lookup_and_publish(
-    pool: web::Data<DbPool>,
-    redis: RedisPool,
-    payer: String,
-    recipient: String,
-    email: String,
-) {
-    let pool: &DbPool = &pool;
-    let r = sqlx::query!(
-        r#"
-           SELECT email
-           FROM users
-           WHERE email != $1 AND (id = $2 OR id = $3)
-           LIMIT 1
-         "#,
-        email,
-        payer,
-        recipient
-    )
-    .fetch_one(pool)
-    .await
-    .expect("id of other");
+/// Lets the group know about the new status of the member, subscribing them to the group when
+/// they joined.
+fn publish_membership(bus: &Bus, group_id: models::GroupId, user: models::User, joined: bool) {
+    let email = user.email.clone();
 
-    let notif_email = r.email;
+    let event = if joined {
+        Event::MemberJoined {
+            group_id,
+            user: user.clone(),
+        }
+    } else {
+        Event::MemberLeft {
+            group_id,
+            user: user.clone(),
+        }
+    };
 
-    spawn(publish_topic(
-        redis,
-        format!("users.{}.notifications", notif_email),
+    spawn(publish_event(
+        bus.clone(),
+        format!("groups.{}.members.{}", group_id, email),
         email.clone(),
+        event,
+    ));
+
+    if joined {
+        spawn(publish_event(
+            bus.clone(),
+            format!("users.{}.groups.{}.joined", email, group_id),
+            email,
+            Event::MemberJoined { group_id, user },
+        ));
+    }
+}
+
+/// Lets the recipients know about the notifications just created for them.
+fn publish_notifications(bus: &Bus, notifications: Vec<models::CreatedNotification>, email: &str) {
+    for notification in notifications {
+        spawn(publish_event(
+            bus.clone(),
+            format!("users.{}.notifications", notification.email),
+            email.to_owned(),
+            Event::NotificationCreated {
+                id: notification.id,
+            },
+        ));
+    }
+}
+
+/// Publishes the drafts that just became expenses and notifies their payments and budget alerts.
+fn notify_published(
+    bus: &Bus,
+    group_id: models::GroupId,
+    expenses: &[models::Expense],
+    notifications: Vec<models::CreatedNotification>,
+    email: &str,
+) {
+    spawn(publish_event(
+        bus.clone(),
+        format!("groups.{}.expenses.drafts", group_id),
+        email.to_owned(),
+        Event::ExpensesPublished {
+            group_id,
+            expense_ids: expenses.iter().filter_map(|e| e.id).collect(),
+        },
     ));
+
+    publish_notifications(bus, notifications, email);
 }
 
 // *****************************************************************************************************
 // *************** HTTP Utils ***************
 // *****************************************************************************************************
 
-fn handle_unknown_redis_error(e: redis::RedisError) -> actix_web::Error {
-    let error = format!("redis error:\n{}", e);
+fn etag(updated_at: chrono::DateTime<chrono::Utc>) -> ETag {
+    ETag(EntityTag::new_strong(
+        updated_at.timestamp_micros().to_string(),
+    ))
+}
+
+/// Versions of the resource accepted by the `If-Match` header, `None` when any of them is.
+fn expected_versions(
+    if_match: Option<web::Header<IfMatch>>,
+) -> Result<Option<Vec<chrono::DateTime<chrono::Utc>>>, Error> {
+    match if_match.map(|h| h.into_inner()) {
+        None => Err(ErrorPreconditionRequired("missing If-Match header")),
+        Some(IfMatch::Any) => Ok(None),
+        Some(IfMatch::Items(tags)) => Ok(Some(
+            tags.iter()
+                .filter_map(|t| t.tag().parse::<i64>().ok())
+                .filter_map(chrono::DateTime::from_timestamp_micros)
+                .collect(),
+        )),
+    }
+}
+
+fn handle_unknown_bus_error(e: BusError) -> actix_web::Error {
+    let error = format!("bus error:\n{}", e);
     eprintln!("{}", &error);
     ErrorInternalServerError(error)
 }
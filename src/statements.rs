@@ -0,0 +1,416 @@
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+use crate::models::{CurrencyId, Expense, ExpenseId};
+
+/// Max difference allowed between amounts to consider a transaction already recorded.
+const TOLERANCE: f64 = 0.005;
+
+const CSV_DATE_FORMAT: &str = "%Y-%m-%d";
+/// two digit years go first, `%Y` would read them as years of the first century
+const QIF_DATE_FORMATS: [&str; 2] = ["%m/%d/%y", "%m/%d/%Y"];
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all(serialize = "snake_case", deserialize = "snake_case"))]
+pub enum StatementFormat {
+    Ofx,
+    Qif,
+    Csv,
+}
+
+#[derive(Deserialize)]
+pub struct StatementImport {
+    pub format: StatementFormat,
+    pub content: String,
+    /// defaults to the currency of the group
+    #[serde(default)]
+    pub currency_id: Option<CurrencyId>,
+    /// chrono format of the dates for QIF and CSV statements
+    #[serde(default)]
+    pub date_format: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Transaction {
+    pub date: NaiveDate,
+    /// negative for debits
+    pub amount: f64,
+    pub description: String,
+}
+
+#[derive(Serialize)]
+pub struct StatementReport {
+    pub drafts: Vec<Expense>,
+    /// debits matching expenses already in the group
+    pub duplicates: Vec<Transaction>,
+    /// credits are not turned into expenses
+    pub skipped: usize,
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmDrafts {
+    pub expense_ids: Vec<ExpenseId>,
+}
+
+pub fn parse_statement(
+    format: StatementFormat,
+    content: &str,
+    date_format: Option<&str>,
+) -> Result<Vec<Transaction>, String> {
+    match format {
+        StatementFormat::Ofx => parse_ofx(content),
+        StatementFormat::Qif => parse_qif(content, date_format),
+        StatementFormat::Csv => parse_csv(content, date_format),
+    }
+}
+
+/// Whether the debit was already recorded as an expense, either published or as a draft.
+pub fn is_duplicate(transaction: &Transaction, expenses: &[Expense]) -> bool {
+    expenses.iter().any(|e| {
+        e.date.date_naive() == transaction.date
+            && (e.amount + transaction.amount).abs() < TOLERANCE
+            && e.description
+                .trim()
+                .eq_ignore_ascii_case(transaction.description.trim())
+    })
+}
+
+/// Handles both SGML (1.x) and XML (2.x) statements, leaf elements are read up to the next tag.
+fn parse_ofx(content: &str) -> Result<Vec<Transaction>, String> {
+    let upper = content.to_ascii_uppercase();
+
+    let mut transactions = Vec::default();
+    let mut offset = 0;
+    while let Some(start) = upper[offset..].find("<STMTTRN>") {
+        let start = offset + start;
+        let end = upper[start..]
+            .find("</STMTTRN>")
+            .map(|end| start + end)
+            .ok_or("unterminated STMTTRN element")?;
+
+        let block = &content[start..end];
+        let upper_block = &upper[start..end];
+        let field = |tag: &str| {
+            upper_block.find(&format!("<{}>", tag)).map(|i| {
+                let value = &block[i + tag.len() + 2..];
+                value[..value.find('<').unwrap_or(value.len())].trim()
+            })
+        };
+
+        let date = field("DTPOSTED").ok_or("transaction without DTPOSTED")?;
+        let date = date
+            .get(..8)
+            .and_then(|d| NaiveDate::parse_from_str(d, "%Y%m%d").ok())
+            .ok_or_else(|| format!("invalid date `{}`", date))?;
+        let amount = field("TRNAMT").ok_or("transaction without TRNAMT")?;
+        let amount = parse_amount(amount)?;
+        let description = field("NAME")
+            .filter(|n| !n.is_empty())
+            .or_else(|| field("MEMO"))
+            .unwrap_or_default();
+
+        transactions.push(Transaction {
+            date,
+            amount,
+            description: description.to_owned(),
+        });
+
+        offset = end;
+    }
+
+    Ok(transactions)
+}
+
+fn parse_qif(content: &str, date_format: Option<&str>) -> Result<Vec<Transaction>, String> {
+    let mut transactions = Vec::default();
+
+    let (mut date, mut amount, mut payee, mut memo) = (None, None, None, None);
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        let Some(code) = line.chars().next() else {
+            continue;
+        };
+        let value = line[code.len_utf8()..].trim();
+
+        match code {
+            // headers like `!Type:Bank`
+            '!' => {}
+            'D' => date = Some(parse_qif_date(value, date_format).map_err(|e| at(i, e))?),
+            'T' | 'U' => amount = Some(parse_amount(value).map_err(|e| at(i, e))?),
+            'P' => payee = Some(value.to_owned()),
+            'M' => memo = Some(value.to_owned()),
+            '^' => {
+                let (Some(date), Some(amount)) = (date.take(), amount.take()) else {
+                    return Err(at(i, "transaction without date or amount".to_owned()));
+                };
+
+                transactions.push(Transaction {
+                    date,
+                    amount,
+                    description: payee.take().or(memo.take()).unwrap_or_default(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    Ok(transactions)
+}
+
+/// QIF exporters write dates as `1/31/2024`, `1/31/24` or `1/31'24`.
+fn parse_qif_date(value: &str, date_format: Option<&str>) -> Result<NaiveDate, String> {
+    let value = value.replace('\'', "/").replace(' ', "");
+
+    let mut formats = date_format.into_iter().chain(QIF_DATE_FORMATS);
+    formats
+        .find_map(|f| NaiveDate::parse_from_str(&value, f).ok())
+        .ok_or_else(|| format!("invalid date `{}`", value))
+}
+
+/// Reads the columns by name, amounts come either signed in an `amount` column or split in
+/// `debit`/`credit` columns.
+fn parse_csv(content: &str, date_format: Option<&str>) -> Result<Vec<Transaction>, String> {
+    let date_format = date_format.unwrap_or(CSV_DATE_FORMAT);
+
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(content.as_bytes());
+
+    let headers = reader
+        .headers()
+        .map_err(|e| e.to_string())?
+        .iter()
+        .map(|h| h.to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    let column = |names: &[&str]| headers.iter().position(|h| names.contains(&h.as_str()));
+
+    let date_i = column(&["date", "posted date", "transaction date", "booking date"])
+        .ok_or("missing date column")?;
+    let description_i = column(&["description", "payee", "name", "details", "memo"])
+        .ok_or("missing description column")?;
+    let amount_i = column(&["amount"]);
+    let debit_i = column(&["debit", "withdrawal", "withdrawals"]);
+    let credit_i = column(&["credit", "deposit", "deposits"]);
+    if amount_i.is_none() && debit_i.is_none() {
+        return Err("missing amount or debit column".to_owned());
+    }
+
+    let mut transactions = Vec::default();
+    for record in reader.records() {
+        let record = record.map_err(|e| e.to_string())?;
+        let line = record.position().map(|p| p.line()).unwrap_or_default();
+        let field = |i: Option<usize>| i.and_then(|i| record.get(i)).unwrap_or_default();
+
+        if field(Some(date_i)).is_empty() {
+            continue;
+        }
+
+        let date = NaiveDate::parse_from_str(field(Some(date_i)), date_format)
+            .map_err(|e| format!("line {}: invalid date: {}", line, e))?;
+
+        let amount = if let Some(amount_i) = amount_i {
+            parse_amount(field(Some(amount_i)))
+        } else if !field(debit_i).is_empty() {
+            parse_amount(field(debit_i)).map(|a| -a.abs())
+        } else {
+            parse_amount(field(credit_i)).map(f64::abs)
+        }
+        .map_err(|e| format!("line {}: {}", line, e))?;
+
+        transactions.push(Transaction {
+            date,
+            amount,
+            description: field(Some(description_i)).to_owned(),
+        });
+    }
+
+    Ok(transactions)
+}
+
+fn parse_amount(value: &str) -> Result<f64, String> {
+    value
+        .replace([',', '$', ' '], "")
+        .parse::<f64>()
+        .map_err(|e| format!("invalid amount `{}`: {}", value, e))
+}
+
+fn at(line: usize, message: String) -> String {
+    format!("line {}: {}", line + 1, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::models::SplitStrategy;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn expense(description: &str, amount: f64, day: u32) -> Expense {
+        Expense {
+            id: Some(1),
+            group_id: Some(1),
+            deleted: false,
+            draft: false,
+            description: description.to_owned(),
+            currency_id: 1,
+            amount,
+            date: Utc.with_ymd_and_hms(2024, 1, day, 12, 0, 0).unwrap(),
+            split_strategy: SplitStrategy::Equally {
+                payer: "a".to_owned(),
+                split_between: vec!["a".to_owned(), "b".to_owned()],
+            },
+            category: None,
+            created_by_id: None,
+            created_at: None,
+            updated_by_id: None,
+            updated_at: None,
+        }
+    }
+
+    #[test]
+    fn parses_sgml_ofx() {
+        let content = "OFXHEADER:100\nDATA:OFXSGML\n<OFX><BANKTRANLIST>\n\
+            <STMTTRN>\n<TRNTYPE>DEBIT\n<DTPOSTED>20240115120000\n<TRNAMT>-42.50\n<NAME>Grocery\n</STMTTRN>\n\
+            <STMTTRN>\n<TRNTYPE>CREDIT\n<DTPOSTED>20240116\n<TRNAMT>100\n<NAME>\n<MEMO>Salary\n</STMTTRN>\n\
+            </BANKTRANLIST></OFX>";
+
+        let transactions = parse_statement(StatementFormat::Ofx, content, None).unwrap();
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].date, date(2024, 1, 15));
+        assert_eq!(transactions[0].amount, -42.5);
+        assert_eq!(transactions[0].description, "Grocery");
+        assert_eq!(transactions[1].amount, 100.0);
+        assert_eq!(transactions[1].description, "Salary");
+    }
+
+    #[test]
+    fn parses_xml_ofx() {
+        let content = r#"<?xml version="1.0"?>
+            <OFX><BANKTRANLIST>
+              <stmttrn><TRNTYPE>DEBIT</TRNTYPE><DTPOSTED>20240201</DTPOSTED><TRNAMT>-9.99</TRNAMT><NAME>Streaming</NAME></stmttrn>
+            </BANKTRANLIST></OFX>"#;
+
+        let transactions = parse_statement(StatementFormat::Ofx, content, None).unwrap();
+
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].date, date(2024, 2, 1));
+        assert_eq!(transactions[0].amount, -9.99);
+        assert_eq!(transactions[0].description, "Streaming");
+    }
+
+    #[test]
+    fn rejects_malformed_ofx() {
+        let unterminated = "<STMTTRN><DTPOSTED>20240101<TRNAMT>-1";
+        assert!(parse_statement(StatementFormat::Ofx, unterminated, None).is_err());
+
+        let without_amount = "<STMTTRN><DTPOSTED>20240101</STMTTRN>";
+        assert!(parse_statement(StatementFormat::Ofx, without_amount, None).is_err());
+
+        let invalid_date = "<STMTTRN><DTPOSTED>2024<TRNAMT>-1</STMTTRN>";
+        assert!(parse_statement(StatementFormat::Ofx, invalid_date, None).is_err());
+    }
+
+    #[test]
+    fn parses_qif() {
+        let content = "!Type:Bank\nD1/31/2024\nT-1,250.00\nPRent\n^\nD2/1'24\nU15\nMRefund\n^\n";
+
+        let transactions = parse_statement(StatementFormat::Qif, content, None).unwrap();
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].date, date(2024, 1, 31));
+        assert_eq!(transactions[0].amount, -1250.0);
+        assert_eq!(transactions[0].description, "Rent");
+        assert_eq!(transactions[1].date, date(2024, 2, 1));
+        assert_eq!(transactions[1].description, "Refund");
+    }
+
+    #[test]
+    fn parses_qif_with_date_format() {
+        let content = "!Type:Bank\nD31.01.2024\nT-5\nPCoffee\n^\n";
+
+        let transactions =
+            parse_statement(StatementFormat::Qif, content, Some("%d.%m.%Y")).unwrap();
+
+        assert_eq!(transactions[0].date, date(2024, 1, 31));
+    }
+
+    #[test]
+    fn rejects_malformed_qif() {
+        let without_amount = "!Type:Bank\nD1/31/2024\nPRent\n^\n";
+        let error = parse_statement(StatementFormat::Qif, without_amount, None).unwrap_err();
+        assert!(error.starts_with("line 4:"), "{}", error);
+
+        let invalid_date = "!Type:Bank\nDyesterday\nT-1\n^\n";
+        assert!(parse_statement(StatementFormat::Qif, invalid_date, None).is_err());
+
+        let invalid_amount = "!Type:Bank\nD1/31/2024\nTten\n^\n";
+        assert!(parse_statement(StatementFormat::Qif, invalid_amount, None).is_err());
+    }
+
+    #[test]
+    fn parses_csv_with_signed_amounts() {
+        let content = "Date,Description,Amount\n2024-03-01,Dinner,-60.00\n,,\n2024-03-02,Transfer,\"1,000\"\n";
+
+        let transactions = parse_statement(StatementFormat::Csv, content, None).unwrap();
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].date, date(2024, 3, 1));
+        assert_eq!(transactions[0].amount, -60.0);
+        assert_eq!(transactions[0].description, "Dinner");
+        assert_eq!(transactions[1].amount, 1000.0);
+    }
+
+    #[test]
+    fn parses_csv_with_debit_and_credit_columns() {
+        let content =
+            "Posted Date,Payee,Debit,Credit\n03/04/2024,Taxi,12.5,\n03/05/2024,Refund,,7\n";
+
+        let transactions =
+            parse_statement(StatementFormat::Csv, content, Some("%m/%d/%Y")).unwrap();
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].date, date(2024, 3, 4));
+        assert_eq!(transactions[0].amount, -12.5);
+        assert_eq!(transactions[1].amount, 7.0);
+    }
+
+    #[test]
+    fn rejects_malformed_csv() {
+        let without_amount = "Date,Description\n2024-03-01,Dinner\n";
+        assert!(parse_statement(StatementFormat::Csv, without_amount, None).is_err());
+
+        let without_date = "Description,Amount\nDinner,-1\n";
+        assert!(parse_statement(StatementFormat::Csv, without_date, None).is_err());
+
+        let invalid_date = "Date,Description,Amount\n01/03/2024,Dinner,-1\n";
+        let error = parse_statement(StatementFormat::Csv, invalid_date, None).unwrap_err();
+        assert!(error.starts_with("line 2:"), "{}", error);
+
+        let invalid_amount = "Date,Description,Amount\n2024-03-01,Dinner,lots\n";
+        assert!(parse_statement(StatementFormat::Csv, invalid_amount, None).is_err());
+    }
+
+    #[test]
+    fn detects_duplicates() {
+        let expenses = vec![expense("Dinner ", 60.0, 1), expense("Taxi", 12.5, 2)];
+        let transaction = |description: &str, amount: f64, day: u32| Transaction {
+            date: date(2024, 1, day),
+            amount,
+            description: description.to_owned(),
+        };
+
+        assert!(is_duplicate(&transaction("dinner", -60.0, 1), &expenses));
+        assert!(is_duplicate(&transaction("TAXI", -12.501, 2), &expenses));
+
+        assert!(!is_duplicate(&transaction("Dinner", -60.0, 2), &expenses));
+        assert!(!is_duplicate(&transaction("Dinner", -61.0, 1), &expenses));
+        assert!(!is_duplicate(&transaction("Lunch", -60.0, 1), &expenses));
+        assert!(!is_duplicate(&transaction("Dinner", -60.0, 1), &[]));
+    }
+}
@@ -0,0 +1,57 @@
+use crate::models::{Expense, GroupStats, SplitStrategy};
+
+/// Category used for the expenses without one.
+const UNCATEGORIZED: &str = "uncategorized";
+
+/// Computes the spending stats out of the expenses of a group, shares are resolved the same way
+/// as for the balances.
+pub fn compute_group_stats(expenses: &[Expense]) -> GroupStats {
+    let mut stats = GroupStats::default();
+
+    for expense in expenses {
+        if let SplitStrategy::Payment { .. } = expense.split_strategy {
+            continue;
+        }
+
+        let currency_id = expense.currency_id;
+
+        *stats.total.entry(currency_id).or_default() += expense.amount;
+
+        let payer = expense.split_strategy.payer();
+        *stats
+            .members
+            .entry(payer.clone())
+            .or_default()
+            .paid
+            .entry(currency_id)
+            .or_default() += expense.amount;
+
+        for (user, share) in expense.split_strategy.shares(expense.amount) {
+            *stats
+                .members
+                .entry(user)
+                .or_default()
+                .consumed
+                .entry(currency_id)
+                .or_default() += share;
+        }
+
+        let category = expense.category.as_deref().unwrap_or(UNCATEGORIZED);
+        *stats
+            .categories
+            .entry(category.to_owned())
+            .or_default()
+            .entry(currency_id)
+            .or_default() += expense.amount;
+
+        let month = expense.date.format("%Y-%m").to_string();
+        *stats
+            .months
+            .entry(month)
+            .or_default()
+            .entry(currency_id)
+            .or_default() += expense.amount;
+    }
+
+    stats
+}
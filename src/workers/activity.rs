@@ -1,73 +1,151 @@
-use std::collections::HashMap;
-use std::env;
-use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use chrono::{DateTime, Utc};
-use futures::lock::Mutex;
 use futures::StreamExt;
-use redis::Commands;
+use tokio::time::sleep;
+use uuid::Uuid;
 
-pub async fn activity_detector() {
+use crate::bus::{Bus, BusError, Subscription};
+use crate::workers::{MAX_BACKOFF, MIN_BACKOFF};
+
+/// Only one replica sweeps at a time.
+const SWEEPER_LEASE: &str = "activity.sweeper";
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+pub async fn activity_detector(bus: Bus) {
     println!("INACTIVITY DETECTOR STARTING");
-    let connspec = env::var("REDIS_URI").expect("REDIS_URI");
-    let redis_client = redis::Client::open(connspec).expect("redis client connected successfully");
-    let mut connection = redis_client.get_connection().expect("redis connection");
 
-    let mut pubsub = redis_client.get_async_pubsub().await.expect("redis conn");
+    // identifies this replica while holding the lease
+    let holder = Uuid::new_v4().to_string();
 
-    let users_updates = Arc::new(Mutex::new(HashMap::<String, DateTime<Utc>>::new()));
+    let mut backoff = MIN_BACKOFF;
 
-    pubsub
-        .subscribe("auth.login")
-        .await
-        .expect("login subscribe success");
+    loop {
+        let started = Instant::now();
 
-    pubsub
-        .subscribe("sync")
-        .await
-        .expect("sync subscribe success");
+        if let Err(e) = detect_activity(&bus, &holder, SWEEP_INTERVAL).await {
+            eprintln!("INACTIVITY DETECTOR DISCONNECTED:\n{}", e);
+        }
 
-    let mut interval = tokio::time::interval(Duration::from_secs(30));
+        // connections that lasted a while start over from the shortest wait
+        if started.elapsed() > MAX_BACKOFF {
+            backoff = MIN_BACKOFF;
+        }
 
-    let mut stream = pubsub.on_message();
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
 
-    loop {
-        tokio::select! {
-          _ = interval.tick() => {
-            let mut deletion = Vec::default();
+/// Records the presence of the users syncing and sweeps the ones gone, only returns on bus
+/// errors.
+async fn detect_activity(
+    bus: &Bus,
+    holder: &str,
+    sweep_interval: Duration,
+) -> Result<(), BusError> {
+    let Subscription {
+        mut subscriber,
+        mut messages,
+    } = bus.subscribe().await?;
 
-            let mut users_updates = users_updates.lock().await;
+    subscriber.subscribe("auth.login").await?;
+    subscriber.subscribe("sync").await?;
 
-            let now = Utc::now();
-            for (user, time) in users_updates.iter() {
-                let diff = now - time;
+    let mut interval = tokio::time::interval(sweep_interval);
 
-                if diff.num_seconds() > 60 {
-                   // user has not synced for more than 60s, they are logged out
-                   deletion.push(user.clone());
-                }
+    loop {
+        tokio::select! {
+          _ = interval.tick() => {
+            // the lease outlives the interval so that the leader keeps it between sweeps
+            let lease = sweep_interval.as_millis() as u64 * 2;
+            let leader = bus.acquire_lease(SWEEPER_LEASE, holder, lease).await?;
+            if !leader {
+                continue;
             }
 
-            for user in deletion {
-                users_updates.remove(&user);
-                connection.publish::<&str, &String, ()>("activity.logout", &user).expect("publish logout");
+            // users that have not synced lately are logged out
+            let users = bus.sweep_presence().await?;
+
+            for user in users {
+                bus.publish("activity.logout", &user).await?;
             }
           },
-          next = stream.next() => {
-             if let Some(msg) = next {
-                 let _channel = msg.get_channel_name();
-                 let user: String = msg.get_payload().expect("login payload");
-
-                 let now = Utc::now();
+          next = messages.next() => {
+             let Some(msg) = next else {
+                 return Err("subscription closed".into());
+             };
+             let user = msg.payload;
 
-                 let result = users_updates.lock().await.insert(user.clone(), now);
+             // every replica gets the message, only the first one to record it announces it
+             let new = bus.touch_presence(&user).await?;
 
-                 if result.is_none () {
-                   connection.publish::<&str, String, ()>("activity.login", user).expect("publish login");
-                 }
+             if new {
+               bus.publish("activity.login", &user).await?;
              }
           }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicI64, Ordering};
+    use std::sync::Arc;
+
+    use futures::stream::BoxStream;
+    use futures::FutureExt;
+
+    use super::*;
+    use crate::bus::{BusMessage, InMemoryBus, PRESENCE_TIMEOUT_SECONDS};
+
+    const SWEEP: Duration = Duration::from_millis(10);
+
+    async fn next(messages: &mut BoxStream<'static, BusMessage>) -> (String, String) {
+        let message = tokio::time::timeout(Duration::from_secs(5), messages.next())
+            .await
+            .expect("message in time")
+            .expect("open subscription");
+
+        (message.topic, message.payload)
+    }
+
+    #[actix_web::test]
+    async fn logs_out_the_users_that_stop_syncing() {
+        let now = Arc::new(AtomicI64::new(1_700_000_000));
+        let clock = now.clone();
+        let bus: Bus = Arc::new(InMemoryBus::with_clock(move || {
+            clock.load(Ordering::Relaxed)
+        }));
+
+        let Subscription {
+            mut subscriber,
+            mut messages,
+        } = bus.subscribe().await.unwrap();
+        subscriber.subscribe("activity.*").await.unwrap();
+
+        let detector = bus.clone();
+        actix_web::rt::spawn(async move { detect_activity(&detector, "holder", SWEEP).await });
+        // lets the detector subscribe
+        sleep(SWEEP).await;
+
+        bus.publish("sync", "user").await.unwrap();
+        assert_eq!(
+            next(&mut messages).await,
+            ("activity.login".into(), "user".into())
+        );
+
+        // still around, syncing again is no new login
+        bus.publish("sync", "user").await.unwrap();
+        sleep(SWEEP).await;
+        now.fetch_add(PRESENCE_TIMEOUT_SECONDS, Ordering::Relaxed);
+        sleep(SWEEP * 3).await;
+        assert!(messages.next().now_or_never().is_none());
+
+        now.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(
+            next(&mut messages).await,
+            ("activity.logout".into(), "user".into())
+        );
+        assert_eq!(bus.online_users().await.unwrap(), Vec::<String>::new());
+    }
+}
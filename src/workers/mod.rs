@@ -1,2 +1,8 @@
+use std::time::Duration;
+
 pub mod activity;
 pub mod sync;
+
+/// Bounds of the wait before subscribing again to the bus, doubled after every failed attempt.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
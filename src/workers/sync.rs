@@ -1,132 +1,288 @@
 use std::collections::{HashMap, HashSet};
-use std::env;
+use std::time::Instant;
 
-use redis::{Commands, PubSub};
+use chrono::{DateTime, Duration, Utc};
+use futures::StreamExt;
+use tokio::time::{interval, sleep};
+use uuid::Uuid;
 
+use crate::bus::{Bus, BusError, BusMessage, Subscriber, Subscription, EVENTS_RETENTION_SECONDS};
+use crate::models::EventPayload;
 use crate::queries::{find_groups, DbPool};
+use crate::workers::{MAX_BACKOFF, MIN_BACKOFF};
 
-pub async fn topics_sync(pool: DbPool) {
+/// Only one replica follows the topics, otherwise the events would get to the logs once per
+/// replica.
+const LEADER_LEASE: &str = "sync.leader";
+const LEASE_MILLIS: u64 = 15_000;
+/// Time between renewals of the lease, and between attempts to take it.
+const LEASE_RENEWAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+pub async fn topics_sync(pool: DbPool, bus: Bus) {
     println!("SYNC DETECTOR STARTING");
-    let connspec = env::var("REDIS_URI").expect("REDIS_URI");
-    let redis_client = redis::Client::open(connspec).expect("redis client connected successfully");
-    let mut connection = redis_client.get_connection().expect("redis connection");
 
-    let mut connection2 = redis_client.get_connection().expect("redis conn");
-    let mut pubsub = connection2.as_pubsub();
+    // identifies this replica while holding the lease
+    let holder = Uuid::new_v4().to_string();
+
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        let started = Instant::now();
+
+        if let Err(e) = stand_by(&bus, &holder, &pool).await {
+            eprintln!("SYNC DETECTOR DISCONNECTED:\n{}", e);
+        }
+
+        // connections that lasted a while start over from the shortest wait
+        if started.elapsed() > MAX_BACKOFF {
+            backoff = MIN_BACKOFF;
+        }
+
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Waits for the lease and follows the topics while holding it, only returns on bus or db errors.
+async fn stand_by(bus: &Bus, holder: &str, pool: &DbPool) -> Result<(), BusError> {
+    loop {
+        let leader = bus
+            .acquire_lease(LEADER_LEASE, holder, LEASE_MILLIS)
+            .await?;
+
+        if leader {
+            println!("SYNC DETECTOR LEADING");
+            follow_topics(bus, holder, pool).await?;
+            println!("SYNC DETECTOR STANDING BY");
+        }
+
+        sleep(LEASE_RENEWAL).await;
+    }
+}
 
-    pubsub
-        .subscribe("activity.login")
-        .expect("login subscribe success");
+/// Follows the topics of the users online while holding the lease. The subscriptions are rebuilt
+/// from the presence of the users, so that any replica can take over.
+async fn follow_topics(bus: &Bus, holder: &str, pool: &DbPool) -> Result<(), BusError> {
+    let Subscription {
+        mut subscriber,
+        mut messages,
+    } = bus.subscribe().await?;
 
-    pubsub
-        .subscribe("activity.logout")
-        .expect("logout subscribe success");
+    subscriber.subscribe("activity.login").await?;
+    subscriber.subscribe("activity.logout").await?;
 
     let mut user_to_topics = HashMap::<String, HashSet<String>>::new();
     let mut topic_to_users = HashMap::<String, HashSet<String>>::new();
+    // users are still followed for a while after logging out so that reconnecting clients can
+    // catch up from the log
+    let mut logged_out = HashMap::<String, DateTime<Utc>>::new();
+
+    // subscribed to logins already, none gets lost while catching up
+    for user in bus.online_users().await? {
+        login(
+            subscriber.as_mut(),
+            &mut topic_to_users,
+            &mut user_to_topics,
+            &user,
+            pool,
+        )
+        .await?;
+    }
+
+    let mut renewal = interval(LEASE_RENEWAL);
 
     // read stream
-    while let Ok(msg) = pubsub.get_message() {
-        let channel = msg.get_channel_name();
-        let payload: String = msg.get_payload().expect("payload");
+    loop {
+        let msg = tokio::select! {
+            _ = renewal.tick() => {
+                let leader = bus.acquire_lease(LEADER_LEASE, holder, LEASE_MILLIS).await?;
+                if !leader {
+                    return Ok(());
+                }
+
+                forget_logged_out(
+                    subscriber.as_mut(),
+                    &mut topic_to_users,
+                    &mut user_to_topics,
+                    &mut logged_out,
+                )
+                .await?;
+                continue;
+            }
+            next = messages.next() => match next {
+                Some(msg) => msg,
+                None => return Err("subscription closed".into()),
+            },
+        };
+
+        let channel = msg.topic.as_str();
 
         match channel {
             "activity.login" => {
-                // query, save and subscribe to all topics for the given user
-                let groups = find_groups(&payload, &pool).await.expect("groups");
-                let mut new_topics = Vec::default();
-                for group in groups {
-                    new_topics.push(format!("groups.{}.*", group.id.unwrap()));
-                }
-                new_topics.push(format!("users.{}.*", &payload));
+                logged_out.remove(&msg.payload);
 
-                add_topics(
-                    &mut pubsub,
+                login(
+                    subscriber.as_mut(),
                     &mut topic_to_users,
                     &mut user_to_topics,
-                    &payload,
-                    new_topics,
-                );
+                    &msg.payload,
+                    pool,
+                )
+                .await?;
             }
             "activity.logout" => {
-                // understand from which topics to unsubscribe and do it
-                let topics = user_to_topics.remove(&payload);
-                if topics.is_none() {
-                    continue;
-                }
-
-                for topic in topics.unwrap() {
-                    let users = topic_to_users.get_mut(&topic);
-                    if let Some(users) = users {
-                        users.remove(&payload);
-
-                        // no more users interested in this topic, unsubscribe
-                        // we don't need to delete the HashSet fttb
-                        if users.is_empty() {
-                            pubsub.punsubscribe(topic).expect("punsubscribe user");
-                        }
-                    }
-                }
+                logged_out.insert(msg.payload.clone(), Utc::now());
             }
             topic if topic.starts_with("groups.") || topic.starts_with("users.") => {
-                if topic.ends_with(".joined") {
-                    // update topics for current user
-
-                    // reading this topic format!("users.{}.groups.{}.joined", email, group_id)
-                    let mut tuqui = topic.split('.');
-                    let group_id = {
-                        for token in &mut tuqui {
-                            if token == "groups" {
-                                break;
-                            }
-                        }
-                        tuqui.next().unwrap()
-                    };
-
-                    let mut new_topics = Vec::default();
-                    new_topics.push(format!("groups.{}.*", group_id));
-
-                    add_topics(
-                        &mut pubsub,
-                        &mut topic_to_users,
-                        &mut user_to_topics,
-                        &payload,
-                        new_topics,
-                    );
-
-                    continue;
-                }
+                route_event(
+                    bus,
+                    subscriber.as_mut(),
+                    &mut topic_to_users,
+                    &mut user_to_topics,
+                    &msg,
+                )
+                .await?;
+            }
+            _ => eprintln!("unknown topic `{}`, skipping message", channel),
+        }
+    }
+}
+
+/// Pushes the event to the logs of the users following the pattern it matched, but its author.
+/// Authors joining a group follow it from then on.
+async fn route_event(
+    bus: &Bus,
+    subscriber: &mut dyn Subscriber,
+    topic_to_users: &mut HashMap<String, HashSet<String>>,
+    user_to_topics: &mut HashMap<String, HashSet<String>>,
+    msg: &BusMessage,
+) -> Result<(), BusError> {
+    let topic = msg.topic.as_str();
 
-                let mut prefix = topic.split('.');
-                let prefix = format!("{}.{}.", prefix.next().unwrap(), prefix.next().unwrap());
+    let Ok(EventPayload { author, .. }) = serde_json::from_str(&msg.payload) else {
+        eprintln!("invalid payload on `{}`: {}", topic, msg.payload);
+        return Ok(());
+    };
 
-                let found = topic_to_users.iter().find(|(t, _)| t.starts_with(&prefix));
+    if topic.ends_with(".joined") {
+        // update topics for current user
 
-                if let Some((_, users)) = found {
-                    for user in users {
-                        if user != &payload {
-                            // only send to user if not the author of the event
-                            connection
-                                .rpush::<String, &str, ()>(format!("events.{}", user), topic)
-                                .expect("publish group topic");
-                        }
-                    }
+        // reading this topic format!("users.{}.groups.{}.joined", email, group_id)
+        let mut tuqui = topic.split('.');
+        let group_id = {
+            for token in &mut tuqui {
+                if token == "groups" {
+                    break;
                 }
             }
-            _ => panic!("unknown topic `{}`", channel),
+            tuqui.next().unwrap()
+        };
+
+        let mut new_topics = Vec::default();
+        new_topics.push(format!("groups.{}.*", group_id));
+
+        add_topics(
+            subscriber,
+            topic_to_users,
+            user_to_topics,
+            &author,
+            new_topics,
+        )
+        .await?;
+
+        return Ok(());
+    }
+
+    if let Some(users) = topic_to_users.get(&msg.pattern) {
+        for user in users {
+            if user != &author {
+                // only send to user if not the author of the event
+                bus.push(user, &msg.payload).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Subscribes to all the topics of the user.
+async fn login(
+    subscriber: &mut dyn Subscriber,
+    topic_to_users: &mut HashMap<String, HashSet<String>>,
+    user_to_topics: &mut HashMap<String, HashSet<String>>,
+    user: &String,
+    pool: &DbPool,
+) -> Result<(), BusError> {
+    // query, save and subscribe to all topics for the given user
+    let groups = find_groups(user, pool).await?;
+    let mut new_topics = Vec::default();
+    for group in groups {
+        new_topics.push(format!("groups.{}.*", group.id.unwrap()));
+    }
+    new_topics.push(format!("users.{}.*", user));
+
+    add_topics(subscriber, topic_to_users, user_to_topics, user, new_topics).await
+}
+
+/// Stops following the users logged out for longer than the retention of the logs.
+async fn forget_logged_out(
+    subscriber: &mut dyn Subscriber,
+    topic_to_users: &mut HashMap<String, HashSet<String>>,
+    user_to_topics: &mut HashMap<String, HashSet<String>>,
+    logged_out: &mut HashMap<String, DateTime<Utc>>,
+) -> Result<(), BusError> {
+    let expired = Utc::now() - Duration::seconds(EVENTS_RETENTION_SECONDS);
+    let gone = logged_out
+        .iter()
+        .filter(|(_, at)| **at < expired)
+        .map(|(user, _)| user.clone())
+        .collect::<Vec<_>>();
+
+    for user in gone {
+        logged_out.remove(&user);
+        remove_user(subscriber, topic_to_users, user_to_topics, &user).await?;
+    }
+
+    Ok(())
+}
+
+/// Stops following the topics of the user.
+async fn remove_user(
+    subscriber: &mut dyn Subscriber,
+    topic_to_users: &mut HashMap<String, HashSet<String>>,
+    user_to_topics: &mut HashMap<String, HashSet<String>>,
+    user: &String,
+) -> Result<(), BusError> {
+    // understand from which topics to unsubscribe and do it
+    let Some(topics) = user_to_topics.remove(user) else {
+        return Ok(());
+    };
+
+    for topic in topics {
+        let users = topic_to_users.get_mut(&topic);
+        if let Some(users) = users {
+            users.remove(user);
+
+            // no more users interested in this topic, unsubscribe
+            // we don't need to delete the HashSet fttb
+            if users.is_empty() {
+                subscriber.unsubscribe(&topic).await?;
+            }
         }
     }
+
+    Ok(())
 }
 
-fn add_topics(
-    pubsub: &mut PubSub,
+async fn add_topics(
+    subscriber: &mut dyn Subscriber,
     topic_to_users: &mut HashMap<String, HashSet<String>>,
     user_to_topics: &mut HashMap<String, HashSet<String>>,
     user: &String,
     new_topics: Vec<String>,
-) {
+) -> Result<(), BusError> {
     for topic in new_topics.iter() {
-        pubsub.psubscribe(topic).expect("psubscribe user");
+        subscriber.subscribe(topic).await?;
 
         if let Some(users) = topic_to_users.get_mut(topic) {
             users.insert(user.clone());
@@ -144,4 +300,123 @@ fn add_topics(
         topics.extend(new_topics);
         user_to_topics.insert(user.clone(), topics);
     }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::FutureExt;
+
+    use super::*;
+    use crate::bus::InMemoryBus;
+    use crate::models::Event;
+
+    /// Follows the topics of the users as `login` would for members of group 1.
+    struct Router {
+        bus: Bus,
+        subscription: Subscription,
+        topic_to_users: HashMap<String, HashSet<String>>,
+        user_to_topics: HashMap<String, HashSet<String>>,
+    }
+
+    impl Router {
+        async fn new(users: &[&str]) -> Self {
+            let bus: Bus = Arc::new(InMemoryBus::default());
+            let mut router = Self {
+                subscription: bus.subscribe().await.unwrap(),
+                bus,
+                topic_to_users: HashMap::new(),
+                user_to_topics: HashMap::new(),
+            };
+
+            for user in users {
+                let topics = vec!["groups.1.*".to_owned(), format!("users.{}.*", user)];
+                add_topics(
+                    router.subscription.subscriber.as_mut(),
+                    &mut router.topic_to_users,
+                    &mut router.user_to_topics,
+                    &user.to_string(),
+                    topics,
+                )
+                .await
+                .unwrap();
+            }
+
+            router
+        }
+
+        /// Publishes the event and routes every message it turned into.
+        async fn publish(&mut self, topic: &str, author: &str, event: Event) {
+            let payload = EventPayload {
+                author: author.to_owned(),
+                event,
+            };
+            let payload = serde_json::to_string(&payload).unwrap();
+            self.bus.publish(topic, &payload).await.unwrap();
+
+            while let Some(Some(msg)) = self.subscription.messages.next().now_or_never() {
+                route_event(
+                    &self.bus,
+                    self.subscription.subscriber.as_mut(),
+                    &mut self.topic_to_users,
+                    &mut self.user_to_topics,
+                    &msg,
+                )
+                .await
+                .unwrap();
+            }
+        }
+
+        async fn log(&self, user: &str) -> Vec<String> {
+            let mut reader = self.bus.reader().await.unwrap();
+            let entries = reader
+                .read_after(user, "0-0", 10, Duration::ZERO)
+                .await
+                .unwrap();
+
+            entries
+                .into_iter()
+                .map(|(_, payload)| serde_json::from_str::<EventPayload>(&payload).unwrap())
+                .map(|payload| payload.author)
+                .collect()
+        }
+    }
+
+    fn deleted(expense_id: i32) -> Event {
+        Event::ExpenseDeleted {
+            group_id: 1,
+            expense_id,
+        }
+    }
+
+    #[actix_web::test]
+    async fn routes_events_to_the_logs_of_the_members_but_the_author() {
+        let mut router = Router::new(&["a.b@c.d", "e.f@g.h"]).await;
+
+        router
+            .publish("groups.1.expenses.2", "a.b@c.d", deleted(2))
+            .await;
+        router
+            .publish("groups.2.expenses.3", "a.b@c.d", deleted(3))
+            .await;
+
+        assert!(router.log("a.b@c.d").await.is_empty());
+        assert_eq!(router.log("e.f@g.h").await, ["a.b@c.d"]);
+    }
+
+    #[actix_web::test]
+    async fn routes_events_of_users_with_dotted_emails_to_them_only() {
+        let mut router = Router::new(&["a.b@c.d", "a.bc@d.e"]).await;
+
+        router
+            .publish("users.a.b@c.d.notifications", "x@y.z", deleted(2))
+            .await;
+
+        assert_eq!(router.log("a.b@c.d").await, ["x@y.z"]);
+        assert!(router.log("a.bc@d.e").await.is_empty());
+    }
 }